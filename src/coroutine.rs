@@ -0,0 +1,430 @@
+use std::cell::{BorrowError, Cell, Ref, RefCell};
+use std::fmt;
+
+use corosensei::stack::DefaultStack;
+use corosensei::{CoroutineResult, Yielder};
+
+use crate::error::{LuaError, LuaResult};
+use crate::value::Value;
+use crate::vm::{CallFrame, Vm, STACK_SEGMENT_SIZE};
+
+/// What a coroutine suspends itself with: it hands its resumer the values
+/// it yields and gets back the arguments of the next resume.
+pub type LuaYielder = Yielder<Vec<Value>, Vec<Value>>;
+
+/// A coroutine body running on a stack of its own.
+type Body = corosensei::Coroutine<Vec<Value>, Vec<Value>, LuaResult<Vec<Value>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoroutineStatus {
+    Suspended,
+    Running,
+    /// Active but not running: it resumed another coroutine.
+    Normal,
+    Dead,
+}
+
+impl CoroutineStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            CoroutineStatus::Suspended => "suspended",
+            CoroutineStatus::Running => "running",
+            CoroutineStatus::Normal => "normal",
+            CoroutineStatus::Dead => "dead",
+        }
+    }
+}
+
+/// How a resumed coroutine gave control back.
+#[derive(Debug)]
+pub enum Outcome {
+    Yielded(Vec<Value>),
+    Returned(Vec<Value>),
+    Failed(LuaError),
+}
+
+/// What a coroutine refers to while it is not running, as the collector
+/// sees it.
+pub struct Contents<'a> {
+    pub function: Ref<'a, Option<Value>>,
+    pub frames: Ref<'a, Vec<CallFrame>>,
+    pub error: Ref<'a, Option<LuaError>>,
+}
+
+/// Everything a coroutine held, taken from it by the collector so that it
+/// can be dropped once every cycle is broken.
+pub struct Remains {
+    _function: Option<Value>,
+    _frames: Vec<CallFrame>,
+    _body: Option<Body>,
+    _error: Option<LuaError>,
+}
+
+/// A Lua thread. Its body runs on a Rust stack of its own, which is what
+/// lets it yield from any depth of nested calls, but on the thread of the
+/// interpreter: resuming switches to that stack and comes back when the
+/// coroutine yields, returns or fails. The stack starts small and grows by
+/// segments as calls nest deeper.
+pub struct Coroutine {
+    status: Cell<CoroutineStatus>,
+    /// The body, until the first resume starts it.
+    function: RefCell<Option<Value>>,
+    /// The Lua call frames of the coroutine while it is suspended, where
+    /// the collector can see them.
+    frames: RefCell<Vec<CallFrame>>,
+    /// The started body, except while it runs.
+    body: RefCell<Option<Body>>,
+    /// The error that killed the coroutine, if one did.
+    error: RefCell<Option<LuaError>>,
+}
+
+impl fmt::Debug for Coroutine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Coroutine")
+            .field("status", &self.status.get())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Coroutine {
+    pub fn new(function: Value) -> Self {
+        Coroutine {
+            status: Cell::new(CoroutineStatus::Suspended),
+            function: RefCell::new(Some(function)),
+            frames: RefCell::new(Vec::new()),
+            body: RefCell::new(None),
+            error: RefCell::new(None),
+        }
+    }
+
+    /// The thread the interpreter starts in, which is always running
+    /// something.
+    pub fn main() -> Self {
+        Coroutine {
+            status: Cell::new(CoroutineStatus::Running),
+            function: RefCell::new(None),
+            frames: RefCell::new(Vec::new()),
+            body: RefCell::new(None),
+            error: RefCell::new(None),
+        }
+    }
+
+    pub fn status(&self) -> CoroutineStatus {
+        self.status.get()
+    }
+
+    pub fn set_status(&self, status: CoroutineStatus) {
+        self.status.set(status);
+    }
+
+    /// Runs the coroutine until it gives control back. The first resume
+    /// starts the body on a new stack, with interpreter state of its own
+    /// that shares everything but the call stack with `vm`.
+    pub fn resume(&self, vm: &Vm, args: Vec<Value>) -> Outcome {
+        let started = self.body.borrow_mut().take();
+        let mut body = match started {
+            Some(body) => body,
+            None => {
+                let Some(function) = self.function.borrow_mut().take() else {
+                    return Outcome::Failed(LuaError::message("cannot resume dead coroutine"));
+                };
+                let Ok(stack) = DefaultStack::new(STACK_SEGMENT_SIZE) else {
+                    self.set_status(CoroutineStatus::Dead);
+                    return Outcome::Failed(LuaError::message("not enough memory"));
+                };
+                let thread = vm.new_thread(&stack);
+                Body::with_stack(stack, move |yielder, args| {
+                    thread.with_yielder(yielder).call_function(function, args)
+                })
+            }
+        };
+
+        match body.resume(args) {
+            CoroutineResult::Yield(values) => {
+                *self.body.borrow_mut() = Some(body);
+                self.set_status(CoroutineStatus::Suspended);
+                Outcome::Yielded(values)
+            }
+            CoroutineResult::Return(result) => {
+                self.set_status(CoroutineStatus::Dead);
+                match result {
+                    Ok(values) => Outcome::Returned(values),
+                    Err(error) => {
+                        *self.error.borrow_mut() = Some(error.clone());
+                        Outcome::Failed(error)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Keeps the frames of the running coroutine while it is suspended.
+    pub fn suspend_frames(&self, frames: Vec<CallFrame>) {
+        *self.frames.borrow_mut() = frames;
+    }
+
+    /// Hands the frames back to the coroutine continuing after a yield.
+    pub fn take_frames(&self) -> Vec<CallFrame> {
+        self.frames.take()
+    }
+
+    /// Fails if the coroutine is being changed.
+    pub fn contents(&self) -> Result<Contents<'_>, BorrowError> {
+        Ok(Contents {
+            function: self.function.try_borrow()?,
+            frames: self.frames.try_borrow()?,
+            error: self.error.try_borrow()?,
+        })
+    }
+
+    /// Takes everything from an unreachable coroutine, leaving it dead.
+    /// Dropping what it returns unwinds the body if it had started.
+    pub fn release(&self) -> Remains {
+        self.set_status(CoroutineStatus::Dead);
+        Remains {
+            _function: self.function.take(),
+            _frames: self.frames.take(),
+            _body: self.body.take(),
+            _error: self.error.take(),
+        }
+    }
+
+    /// Kills a suspended or dead coroutine, unwinding whatever it was in
+    /// the middle of. Returns the frames it was suspended in, whose
+    /// to-be-closed variables are left to close, and the error that killed
+    /// it; closing it again returns neither.
+    pub fn close(&self) -> (Vec<CallFrame>, Option<LuaError>) {
+        let body = self.body.borrow_mut().take();
+        drop(body);
+        self.function.borrow_mut().take();
+        self.set_status(CoroutineStatus::Dead);
+        (self.frames.take(), self.error.take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::run_chunk;
+    use crate::value::Value;
+
+    fn strings(values: &[&str]) -> Result<Vec<Value>, String> {
+        Ok(values
+            .iter()
+            .map(|s| Value::String(s.to_string()))
+            .collect())
+    }
+
+    #[test]
+    fn yields_cross_nested_calls() {
+        let results = run_chunk(
+            "local function walk(n)
+               if n > 0 then coroutine.yield(n) walk(n - 1) end
+             end
+             local sum = 0
+             for n in coroutine.wrap(function() walk(100) end) do sum = sum + n end
+             return sum",
+        );
+        assert_eq!(results, Ok(vec![Value::Integer(5050)]));
+    }
+
+    #[test]
+    fn resume_and_yield_exchange_values() {
+        let results = run_chunk(
+            "local co = coroutine.create(function(a, b)
+               local c = coroutine.yield(a + b)
+               return c * 2
+             end)
+             local _, sum = coroutine.resume(co, 1, 2)
+             local _, double = coroutine.resume(co, 10)
+             return sum, double",
+        );
+        assert_eq!(results, Ok(vec![Value::Integer(3), Value::Integer(20)]));
+    }
+
+    #[test]
+    fn status_follows_the_coroutine() {
+        let results = run_chunk(
+            "local seen = {}
+             local outer
+             local inner = coroutine.create(function()
+               seen[#seen + 1] = coroutine.status(outer)
+             end)
+             outer = coroutine.create(function()
+               seen[#seen + 1] = coroutine.status(outer)
+               coroutine.resume(inner)
+               coroutine.yield()
+             end)
+             seen[#seen + 1] = coroutine.status(outer)
+             coroutine.resume(outer)
+             seen[#seen + 1] = coroutine.status(outer)
+             coroutine.resume(outer)
+             seen[#seen + 1] = coroutine.status(outer)
+             return seen[1], seen[2], seen[3], seen[4], seen[5]",
+        );
+        assert_eq!(
+            results,
+            strings(&["suspended", "running", "normal", "suspended", "dead"])
+        );
+    }
+
+    #[test]
+    fn errors_kill_the_coroutine() {
+        let results = run_chunk(
+            "local co = coroutine.create(function() error('boom', 0) end)
+             local ok, message = coroutine.resume(co)
+             return ok, message, coroutine.status(co), coroutine.resume(co)",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Boolean(false),
+                Value::String("boom".to_string()),
+                Value::String("dead".to_string()),
+                Value::Boolean(false),
+                Value::String("cannot resume dead coroutine".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn only_coroutines_can_yield() {
+        let results = run_chunk(
+            "local _, main = coroutine.running()
+             local co = coroutine.wrap(function()
+               local _, inside = coroutine.running()
+               return coroutine.isyieldable(), inside
+             end)
+             return coroutine.isyieldable(), main, co()",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Boolean(false),
+                Value::Boolean(true),
+                Value::Boolean(true),
+                Value::Boolean(false),
+            ])
+        );
+        assert_eq!(
+            run_chunk("return pcall(coroutine.yield, 1)"),
+            Ok(vec![
+                Value::Boolean(false),
+                Value::String("attempt to yield from outside a coroutine".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn many_coroutines_can_be_suspended_at_once() {
+        let results = run_chunk(
+            "local threads = {}
+             for i = 1, 10000 do
+               threads[i] = coroutine.create(function() coroutine.yield() return i end)
+               coroutine.resume(threads[i])
+             end
+             local sum = 0
+             for i = 1, #threads do sum = sum + select(2, coroutine.resume(threads[i])) end
+             return sum",
+        );
+        assert_eq!(results, Ok(vec![Value::Integer(50005000)]));
+    }
+
+    #[test]
+    fn deep_calls_grow_the_coroutine_stack() {
+        let results = run_chunk(
+            "local function down(n)
+               if n == 0 then return coroutine.yield('bottom') end
+               return down(n - 1)
+             end
+             local co = coroutine.wrap(function() return down(900) end)
+             return co(), co('back')",
+        );
+        assert_eq!(results, strings(&["bottom", "back"]));
+    }
+
+    #[test]
+    fn closing_runs_pending_close_handlers() {
+        let results = run_chunk(
+            "local log = {}
+             local function closer(name)
+               return setmetatable({}, {__close = function(_, err)
+                 log[#log + 1] = name .. ':' .. tostring(err)
+               end})
+             end
+             local function iterate()
+               return function() coroutine.yield() end, nil, nil, closer('loop')
+             end
+             local co = coroutine.create(function()
+               local outer <close> = closer('outer')
+               local function inner()
+                 local a <close> = closer('a')
+                 local b <close> = closer('b')
+                 for _ in iterate() do end
+               end
+               inner()
+             end)
+             coroutine.resume(co)
+             local ok = coroutine.close(co)
+             return ok, coroutine.status(co), log[1], log[2], log[3], log[4]",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Boolean(true),
+                Value::String("dead".to_string()),
+                Value::String("loop:nil".to_string()),
+                Value::String("b:nil".to_string()),
+                Value::String("a:nil".to_string()),
+                Value::String("outer:nil".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn closing_reports_errors_of_close_handlers() {
+        let results = run_chunk(
+            "local seen
+             local co = coroutine.create(function()
+               local first <close> = setmetatable({}, {__close = function(_, err)
+                 seen = err
+               end})
+               local second <close> = setmetatable({}, {__close = function()
+                 error('cannot close', 0)
+               end})
+               coroutine.yield()
+             end)
+             coroutine.resume(co)
+             local ok, message = coroutine.close(co)
+             return ok, message, seen, coroutine.close(co)",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Boolean(false),
+                Value::String("cannot close".to_string()),
+                Value::String("cannot close".to_string()),
+                Value::Boolean(true),
+            ])
+        );
+    }
+
+    #[test]
+    fn wrapped_coroutines_propagate_errors_unchanged() {
+        let results = run_chunk(
+            "local t = {}
+             local failing = coroutine.wrap(function() error('boom') end)
+             local _, message = pcall(failing)
+             local _, value = pcall(coroutine.wrap(function() error(t) end))
+             local _, dead = pcall(failing)
+             return message, value == t, dead",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::String("test:2: boom".to_string()),
+                Value::Boolean(true),
+                Value::String("cannot resume dead coroutine".to_string()),
+            ])
+        );
+    }
+}
@@ -0,0 +1,35 @@
+use std::fmt;
+
+use crate::value::Value;
+
+/// An error raised while running Lua code. Any value can be raised, though
+/// errors are usually message strings.
+#[derive(Debug, Clone)]
+pub struct LuaError {
+    pub value: Value,
+}
+
+pub type LuaResult<T> = Result<T, LuaError>;
+
+impl LuaError {
+    pub fn new(value: Value) -> Self {
+        LuaError { value }
+    }
+
+    pub fn message(message: impl Into<String>) -> Self {
+        LuaError::new(Value::String(message.into()))
+    }
+}
+
+/// Describes the error for the user, like the standalone interpreter does
+/// for an error that nothing caught.
+impl fmt::Display for LuaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            value @ (Value::String(_) | Value::Integer(_) | Value::Number(_)) => {
+                write!(f, "{}", value)
+            }
+            value => write!(f, "(error object is a {} value)", value.type_name()),
+        }
+    }
+}
@@ -0,0 +1,877 @@
+use std::cell::{BorrowError, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::mem::size_of;
+use std::rc::{Rc, Weak};
+
+use crate::coroutine::{Coroutine, Remains};
+use crate::table::LuaTable;
+use crate::value::{Function, Value, Variable};
+
+/// Collections are never due before this many objects are tracked.
+const MIN_THRESHOLD: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GcMode {
+    /// Every collection examines all tracked objects in one go.
+    Full,
+    /// Most collections only examine the objects tracked since the last
+    /// one.
+    Generational,
+}
+
+impl GcMode {
+    /// The name `collectgarbage` knows the mode by. Full collections stand
+    /// in for the reference implementation's incremental ones, so scripts
+    /// select them as "incremental".
+    pub fn name(self) -> &'static str {
+        match self {
+            GcMode::Full => "incremental",
+            GcMode::Generational => "generational",
+        }
+    }
+}
+
+/// An object that can take part in a reference cycle: everything else a
+/// value holds is either immutable or owned outright.
+#[derive(Debug)]
+enum Object {
+    Table(Weak<RefCell<LuaTable>>),
+    /// A local variable, possibly captured by closures.
+    Cell(Weak<RefCell<Value>>),
+    /// The variables captured by a Lua closure.
+    Closure(Weak<HashMap<String, Variable>>),
+    /// The values bound to a native closure.
+    Upvalues(Weak<Vec<Value>>),
+    /// A coroutine, which holds the frames of the calls it is suspended in.
+    Thread(Weak<Coroutine>),
+}
+
+/// A tracked object held alive for the duration of a collection.
+enum Live {
+    Table(Rc<RefCell<LuaTable>>),
+    Cell(Rc<RefCell<Value>>),
+    Closure(Rc<HashMap<String, Variable>>),
+    Upvalues(Rc<Vec<Value>>),
+    Thread(Rc<Coroutine>),
+}
+
+impl Object {
+    fn upgrade(&self) -> Option<Live> {
+        match self {
+            Object::Table(t) => t.upgrade().map(Live::Table),
+            Object::Cell(c) => c.upgrade().map(Live::Cell),
+            Object::Closure(c) => c.upgrade().map(Live::Closure),
+            Object::Upvalues(u) => u.upgrade().map(Live::Upvalues),
+            Object::Thread(t) => t.upgrade().map(Live::Thread),
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        match self {
+            Object::Table(t) => t.strong_count() > 0,
+            Object::Cell(c) => c.strong_count() > 0,
+            Object::Closure(c) => c.strong_count() > 0,
+            Object::Upvalues(u) => u.strong_count() > 0,
+            Object::Thread(t) => t.strong_count() > 0,
+        }
+    }
+}
+
+impl Live {
+    fn downgrade(&self) -> Object {
+        match self {
+            Live::Table(t) => Object::Table(Rc::downgrade(t)),
+            Live::Cell(c) => Object::Cell(Rc::downgrade(c)),
+            Live::Closure(c) => Object::Closure(Rc::downgrade(c)),
+            Live::Upvalues(u) => Object::Upvalues(Rc::downgrade(u)),
+            Live::Thread(t) => Object::Thread(Rc::downgrade(t)),
+        }
+    }
+
+    fn address(&self) -> usize {
+        match self {
+            Live::Table(t) => Rc::as_ptr(t) as usize,
+            Live::Cell(c) => Rc::as_ptr(c) as usize,
+            Live::Closure(c) => Rc::as_ptr(c) as usize,
+            Live::Upvalues(u) => Rc::as_ptr(u) as usize,
+            Live::Thread(t) => Rc::as_ptr(t) as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Live::Table(t) => Rc::strong_count(t),
+            Live::Cell(c) => Rc::strong_count(c),
+            Live::Closure(c) => Rc::strong_count(c),
+            Live::Upvalues(u) => Rc::strong_count(u),
+            Live::Thread(t) => Rc::strong_count(t),
+        }
+    }
+
+    /// Roughly how many bytes the object takes, along with the strings it
+    /// holds but not the objects it refers to.
+    fn size(&self) -> usize {
+        // Next to the object, its allocation holds the reference counts
+        let counts = 2 * size_of::<usize>();
+        let size = match self {
+            Live::Table(t) => {
+                size_of::<RefCell<LuaTable>>()
+                    + t.try_borrow().map_or(0, |table| table.allocated_size())
+            }
+            Live::Cell(c) => {
+                size_of::<RefCell<Value>>() + c.try_borrow().map_or(0, |value| string_size(&value))
+            }
+            Live::Closure(c) => {
+                size_of::<HashMap<String, Variable>>()
+                    + c.capacity() * size_of::<(String, Variable)>()
+                    + c.keys().map(String::capacity).sum::<usize>()
+            }
+            Live::Upvalues(u) => {
+                size_of::<Vec<Value>>()
+                    + u.capacity() * size_of::<Value>()
+                    + u.iter().map(string_size).sum::<usize>()
+            }
+            Live::Thread(_) => size_of::<Coroutine>(),
+        };
+        counts + size
+    }
+
+    /// Reports the address of every object this one holds a reference to,
+    /// once per reference, given the addresses of the tracked cells. Fails
+    /// if the object is being modified.
+    fn for_each_reference(&self, cells: &HashSet<usize>, f: &mut dyn FnMut(usize)) -> bool {
+        match self {
+            Live::Table(t) => {
+                let Ok(table) = t.try_borrow() else {
+                    return false;
+                };
+                if let Some(metatable) = table.metatable() {
+                    f(Rc::as_ptr(&metatable) as usize);
+                }
+                table.references().for_each(|value| reference(value, f));
+            }
+            Live::Cell(c) => {
+                let Ok(value) = c.try_borrow() else {
+                    return false;
+                };
+                reference(&value, f);
+            }
+            Live::Closure(c) => c.values().for_each(|cell| f(Rc::as_ptr(cell) as usize)),
+            Live::Upvalues(u) => u.iter().for_each(|value| reference(value, f)),
+            // Only a suspended coroutine keeps its frames here; those of a
+            // running one are on its stack, and it is a root meanwhile
+            Live::Thread(t) => {
+                let Ok(contents) = t.contents() else {
+                    return false;
+                };
+                if let Some(function) = &*contents.function {
+                    reference(function, f);
+                }
+                if let Some(error) = &*contents.error {
+                    reference(&error.value, f);
+                }
+                // A variable no closure captured belongs to its frame alone,
+                // so what it holds counts as held by the coroutine
+                let mut own_cells = HashSet::new();
+                for frame in contents.frames.iter() {
+                    for cell in frame.variables() {
+                        let address = Rc::as_ptr(cell) as usize;
+                        if cells.contains(&address) {
+                            f(address);
+                        } else if own_cells.insert(address) {
+                            let Ok(value) = cell.try_borrow() else {
+                                return false;
+                            };
+                            reference(&value, f);
+                        }
+                    }
+                    f(Rc::as_ptr(frame.upvalues()) as usize);
+                    frame.values().for_each(|value| reference(value, f));
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Bytes the text of a string value takes.
+pub fn string_size(value: &Value) -> usize {
+    match value {
+        Value::String(s) => s.capacity(),
+        _ => 0,
+    }
+}
+
+fn reference(value: &Value, f: &mut dyn FnMut(usize)) {
+    if let Some(address) = address(value) {
+        f(address);
+    }
+}
+
+/// The address of the tracked object a value refers to, if it may refer
+/// to one.
+fn address(value: &Value) -> Option<usize> {
+    match value {
+        Value::Table(t) => Some(Rc::as_ptr(t) as usize),
+        Value::Function(Function::UserDefined { closure, .. }) => {
+            Some(Rc::as_ptr(closure) as usize)
+        }
+        Value::Function(Function::NativeClosure { upvalues, .. }) => {
+            Some(Rc::as_ptr(upvalues) as usize)
+        }
+        Value::Thread(t) => Some(Rc::as_ptr(t) as usize),
+        _ => None,
+    }
+}
+
+/// Which references of a table with a `__mode` do not keep their target
+/// alive.
+#[derive(Debug, Clone, Copy)]
+struct Weakness {
+    keys: bool,
+    values: bool,
+}
+
+fn weakness(table: &RefCell<LuaTable>) -> Result<Option<Weakness>, BorrowError> {
+    let Some(metatable) = table.try_borrow()?.metatable() else {
+        return Ok(None);
+    };
+    let Value::String(mode) = metatable
+        .try_borrow()?
+        .get(&Value::String("__mode".to_string()))
+    else {
+        return Ok(None);
+    };
+    let weakness = Weakness {
+        keys: mode.contains('k'),
+        values: mode.contains('v'),
+    };
+    Ok((weakness.keys || weakness.values).then_some(weakness))
+}
+
+/// The mark phase of a collection over the objects in `positions`.
+struct Marker<'a> {
+    positions: &'a HashMap<usize, usize>,
+    cells: &'a HashSet<usize>,
+    marked: Vec<bool>,
+    pending: Vec<usize>,
+}
+
+impl Marker<'_> {
+    fn mark(&mut self, address: usize) {
+        if let Some(&i) = self.positions.get(&address) {
+            if !self.marked[i] {
+                self.marked[i] = true;
+                self.pending.push(i);
+            }
+        }
+    }
+
+    fn mark_value(&mut self, value: &Value) {
+        if let Some(address) = address(value) {
+            self.mark(address);
+        }
+    }
+
+    /// Whether a value is known to survive the collection; anything that is
+    /// not one of the collected objects does.
+    fn is_alive(&self, value: &Value) -> bool {
+        address(value)
+            .and_then(|address| self.positions.get(&address))
+            .is_none_or(|&i| self.marked[i])
+    }
+
+    /// Marks everything the pending objects reach, through ephemeron
+    /// tables as well.
+    fn mark_all(
+        &mut self,
+        live: &[Live],
+        weak: &[Option<Weakness>],
+        ephemerons: &[&Rc<RefCell<LuaTable>>],
+    ) {
+        self.propagate(live, weak);
+        // Marking the value of an ephemeron may make the key of another
+        // one reachable, so go over them until nothing changes
+        loop {
+            for table in ephemerons {
+                let i = self.positions[&(Rc::as_ptr(table) as usize)];
+                if self.marked[i] {
+                    for (key, value) in table.borrow().hash_fields() {
+                        if self.is_alive(key) {
+                            self.mark_value(value);
+                        }
+                    }
+                }
+            }
+            if self.pending.is_empty() {
+                break;
+            }
+            self.propagate(live, weak);
+        }
+    }
+
+    /// Marks everything the pending objects reach, except through the
+    /// fields of ephemeron tables whose keys are not marked yet.
+    fn propagate(&mut self, live: &[Live], weak: &[Option<Weakness>]) {
+        while let Some(i) = self.pending.pop() {
+            match (&live[i], weak[i]) {
+                (Live::Table(t), Some(weakness)) => self.trace_weak(&t.borrow(), weakness),
+                (object, _) => {
+                    object.for_each_reference(self.cells, &mut |address| self.mark(address));
+                }
+            }
+        }
+    }
+
+    /// Marks what a weak table holds strongly. With weak keys and strong
+    /// values the table is an ephemeron table: a value is only reachable
+    /// through it once its key is reachable some other way.
+    fn trace_weak(&mut self, table: &LuaTable, weakness: Weakness) {
+        if let Some(metatable) = table.metatable() {
+            self.mark(Rc::as_ptr(&metatable) as usize);
+        }
+        if !weakness.values {
+            for value in table.array() {
+                self.mark_value(value);
+            }
+        }
+        for (key, value) in table.hash_fields() {
+            if !weakness.keys {
+                self.mark_value(key);
+            }
+            if !weakness.values && (!weakness.keys || self.is_alive(key)) {
+                self.mark_value(value);
+            }
+        }
+    }
+}
+
+/// Reclaims reference cycles, which reference counting alone never frees.
+///
+/// Values stay reference counted; the collector only keeps weak references
+/// to the objects that can be part of a cycle. A collection counts, for
+/// each of them, the references held by other tracked objects; any
+/// reference beyond those comes from outside (globals, call frames, values
+/// on the Rust stack) and makes the object a root. Objects not reachable
+/// from a root are garbage, and clearing the garbage tables and variables
+/// breaks their cycles so reference counting can free them.
+///
+/// Each collection stops the program until it is done; the modes only
+/// decide when one is due and, in generational mode, whether it examines
+/// all objects or only those created since the last one.
+#[derive(Debug)]
+pub struct Collector {
+    objects: Vec<Object>,
+    /// Objects before this position survived a collection. Generational
+    /// mode only examines the others in its minor collections.
+    young_start: usize,
+    /// Number of tracked objects that makes the next collection due.
+    threshold: usize,
+    /// Objects surviving the last full collection.
+    live_after_major: usize,
+    running: bool,
+    mode: GcMode,
+    pause: usize,
+    minor_multiplier: usize,
+    major_multiplier: usize,
+    /// Tables that were given a metatable with a `__gc` field, in that
+    /// order. The collector holds them so that they are not freed before their
+    /// finalizers run.
+    finalizable: Vec<Rc<RefCell<LuaTable>>>,
+    finalizable_addresses: HashSet<usize>,
+    /// Addresses of the tracked cells. Only variables captured by closures
+    /// are tracked; the others belong to a single call frame.
+    cells: HashSet<usize>,
+    /// Tables found unreachable whose finalizers have yet to run.
+    pending_finalizers: Vec<Rc<RefCell<LuaTable>>>,
+}
+
+impl Collector {
+    pub fn new() -> Self {
+        Collector {
+            objects: Vec::new(),
+            young_start: 0,
+            threshold: MIN_THRESHOLD,
+            live_after_major: 0,
+            running: true,
+            mode: GcMode::Full,
+            pause: 200,
+            minor_multiplier: 20,
+            major_multiplier: 100,
+            finalizable: Vec::new(),
+            finalizable_addresses: HashSet::new(),
+            cells: HashSet::new(),
+            pending_finalizers: Vec::new(),
+        }
+    }
+
+    pub fn track_table(&mut self, table: &Rc<RefCell<LuaTable>>) {
+        self.track(Object::Table(Rc::downgrade(table)));
+    }
+
+    /// Tracks a variable captured by a closure. Tracking it again has no
+    /// effect.
+    pub fn track_cell(&mut self, cell: &Variable) {
+        if self.cells.insert(Rc::as_ptr(cell) as usize) {
+            self.track(Object::Cell(Rc::downgrade(cell)));
+        }
+    }
+
+    pub fn track_closure(&mut self, closure: &Rc<HashMap<String, Variable>>) {
+        self.track(Object::Closure(Rc::downgrade(closure)));
+    }
+
+    pub fn track_upvalues(&mut self, upvalues: &Rc<Vec<Value>>) {
+        self.track(Object::Upvalues(Rc::downgrade(upvalues)));
+    }
+
+    pub fn track_thread(&mut self, thread: &Rc<Coroutine>) {
+        self.track(Object::Thread(Rc::downgrade(thread)));
+    }
+
+    /// Registers a table for finalization when it becomes unreachable.
+    /// Registering it again has no effect.
+    pub fn track_finalizer(&mut self, table: &Rc<RefCell<LuaTable>>) {
+        if self
+            .finalizable_addresses
+            .insert(Rc::as_ptr(table) as usize)
+        {
+            self.finalizable.push(table.clone());
+        }
+    }
+
+    pub fn has_pending_finalizers(&self) -> bool {
+        !self.pending_finalizers.is_empty()
+    }
+
+    /// The tables whose finalizers are due, in the order to run them.
+    pub fn take_pending_finalizers(&mut self) -> Vec<Rc<RefCell<LuaTable>>> {
+        std::mem::take(&mut self.pending_finalizers)
+    }
+
+    /// Every table still waiting for its finalizer, for when the
+    /// interpreter shuts down.
+    pub fn take_all_finalizers(&mut self) -> Vec<Rc<RefCell<LuaTable>>> {
+        self.finalizable_addresses.clear();
+        let mut tables = self.take_pending_finalizers();
+        tables.extend(std::mem::take(&mut self.finalizable).into_iter().rev());
+        tables
+    }
+
+    fn track(&mut self, object: Object) {
+        self.objects.push(object);
+        if self.running && self.objects.len() >= self.threshold {
+            self.collect_due();
+        }
+    }
+
+    /// Runs the collection that is due: always a full one in full mode,
+    /// and in generational mode a minor one unless the surviving objects
+    /// have grown enough since the last full collection. Returns whether it
+    /// was a full one.
+    pub fn collect_due(&mut self) -> bool {
+        match self.mode {
+            GcMode::Full => self.collect(),
+            GcMode::Generational => {
+                let major_limit = self.live_after_major * (100 + self.major_multiplier) / 100;
+                if self.young_start > major_limit.max(MIN_THRESHOLD) {
+                    self.collect();
+                } else {
+                    self.collect_from(self.young_start);
+                    self.set_threshold();
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// A full collection.
+    pub fn collect(&mut self) {
+        self.collect_from(0);
+        self.live_after_major = self.objects.len();
+        self.set_threshold();
+    }
+
+    fn set_threshold(&mut self) {
+        let live = self.objects.len();
+        let growth = match self.mode {
+            GcMode::Full => live * self.pause.saturating_sub(100) / 100,
+            GcMode::Generational => live * self.minor_multiplier / 100,
+        };
+        self.threshold = (live + growth).max(MIN_THRESHOLD);
+    }
+
+    /// Collects the objects tracked from position `start` on. References
+    /// from older objects count as coming from outside, so a minor
+    /// collection never frees anything an older object still refers to.
+    fn collect_from(&mut self, start: usize) {
+        self.collect_objects_from(start);
+        // The cells no longer tracked may be freed, and their addresses
+        // reused
+        self.cells = self
+            .objects
+            .iter()
+            .filter_map(|object| match object {
+                Object::Cell(c) => Some(c.as_ptr() as usize),
+                _ => None,
+            })
+            .collect();
+    }
+
+    fn collect_objects_from(&mut self, start: usize) {
+        let young = self.objects.split_off(start.min(self.objects.len()));
+        self.objects.retain(Object::is_alive);
+        let live: Vec<Live> = young.iter().filter_map(Object::upgrade).collect();
+        drop(young);
+
+        let positions: HashMap<usize, usize> = live
+            .iter()
+            .enumerate()
+            .map(|(i, object)| (object.address(), i))
+            .collect();
+
+        let mut internal = vec![0; live.len()];
+        // The collector's own hold on tables with finalizers does not keep them
+        // alive
+        for table in &self.finalizable {
+            if let Some(&i) = positions.get(&(Rc::as_ptr(table) as usize)) {
+                internal[i] += 1;
+            }
+        }
+        let mut consistent = true;
+        for object in &live {
+            consistent &= object.for_each_reference(&self.cells, &mut |address| {
+                if let Some(&i) = positions.get(&address) {
+                    internal[i] += 1;
+                }
+            });
+        }
+        if !consistent {
+            // Something is in the middle of changing a table; try again
+            // another time
+            self.objects.extend(live.iter().map(Live::downgrade));
+            self.young_start = self.objects.len();
+            return;
+        }
+
+        let weak: Result<Vec<Option<Weakness>>, BorrowError> = live
+            .iter()
+            .map(|object| match object {
+                Live::Table(t) => weakness(t),
+                _ => Ok(None),
+            })
+            .collect();
+        let Ok(weak) = weak else {
+            self.objects.extend(live.iter().map(Live::downgrade));
+            self.young_start = self.objects.len();
+            return;
+        };
+
+        // `live` itself holds one reference to each object
+        let marked: Vec<bool> = live
+            .iter()
+            .zip(&internal)
+            .map(|(object, &internal)| object.strong_count() - 1 > internal)
+            .collect();
+        let mut marker = Marker {
+            positions: &positions,
+            cells: &self.cells,
+            pending: (0..live.len()).filter(|&i| marked[i]).collect(),
+            marked,
+        };
+        let ephemerons: Vec<&Rc<RefCell<LuaTable>>> = live
+            .iter()
+            .zip(&weak)
+            .filter_map(|(object, weakness)| match (object, weakness) {
+                (Live::Table(t), Some(weakness)) if weakness.keys && !weakness.values => Some(t),
+                _ => None,
+            })
+            .collect();
+        marker.mark_all(&live, &weak, &ephemerons);
+
+        // Weak values referring to objects about to be finalized are
+        // cleared before the objects are resurrected, as in the reference
+        // implementation; weak keys only once the objects are collected
+        for (i, object) in live.iter().enumerate() {
+            if let (Live::Table(t), Some(weakness), true) = (object, weak[i], marker.marked[i]) {
+                if weakness.values {
+                    t.borrow_mut().retain(|_, value| marker.is_alive(value));
+                }
+            }
+        }
+
+        // Unreachable tables with finalizers, and everything they refer
+        // to, survive until their finalizers have run
+        let (due, reachable): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.finalizable).into_iter().partition(|t| {
+                positions
+                    .get(&(Rc::as_ptr(t) as usize))
+                    .is_some_and(|&i| !marker.marked[i])
+            });
+        for table in &due {
+            marker.mark(Rc::as_ptr(table) as usize);
+            self.finalizable_addresses
+                .remove(&(Rc::as_ptr(table) as usize));
+        }
+        marker.mark_all(&live, &weak, &ephemerons);
+        self.finalizable = reachable;
+        // Finalizers run in the reverse order of their registration
+        self.pending_finalizers.extend(due.into_iter().rev());
+
+        // Surviving weak tables drop the fields that refer to garbage
+        for (i, object) in live.iter().enumerate() {
+            if let (Live::Table(t), Some(weakness), true) = (object, weak[i], marker.marked[i]) {
+                t.borrow_mut().retain(|key, value| {
+                    (!weakness.keys || marker.is_alive(key))
+                        && (!weakness.values || marker.is_alive(value))
+                });
+            }
+        }
+        let marked = marker.marked;
+
+        // The contents are dropped only after every cycle is broken
+        let mut released_tables = Vec::new();
+        let mut released_values = Vec::new();
+        let mut released_threads: Vec<Remains> = Vec::new();
+        for (object, &marked) in live.iter().zip(&marked) {
+            if marked {
+                self.objects.push(object.downgrade());
+                continue;
+            }
+            match object {
+                Live::Table(t) => released_tables.push(std::mem::take(&mut *t.borrow_mut())),
+                Live::Cell(c) => released_values.push(c.replace(Value::Nil)),
+                Live::Thread(t) => released_threads.push(t.release()),
+                Live::Closure(_) | Live::Upvalues(_) => {}
+            }
+        }
+        self.young_start = self.objects.len();
+        drop(released_tables);
+        drop(released_values);
+        drop(released_threads);
+    }
+
+    /// Roughly how many bytes the tracked objects take, the figure
+    /// `collectgarbage("count")` reports.
+    pub fn allocated_bytes(&self) -> usize {
+        self.objects
+            .iter()
+            .filter_map(Object::upgrade)
+            .map(|object| object.size())
+            .sum()
+    }
+
+    pub fn mode(&self) -> GcMode {
+        self.mode
+    }
+
+    /// Switches to full mode; zero leaves the pause unchanged. Collections
+    /// are never split into steps, so there is no step size to set.
+    pub fn set_full(&mut self, pause: usize) {
+        self.mode = GcMode::Full;
+        if pause != 0 {
+            self.pause = pause;
+        }
+        self.set_threshold();
+    }
+
+    /// Switches to generational mode; zero leaves a parameter unchanged.
+    pub fn set_generational(&mut self, minor_multiplier: usize, major_multiplier: usize) {
+        self.mode = GcMode::Generational;
+        if minor_multiplier != 0 {
+            self.minor_multiplier = minor_multiplier;
+        }
+        if major_multiplier != 0 {
+            self.major_multiplier = major_multiplier;
+        }
+        self.set_threshold();
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn set_running(&mut self, running: bool) {
+        self.running = running;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::run_chunk;
+    use crate::value::Value;
+
+    #[test]
+    fn reference_cycles_are_reclaimed() {
+        let results = run_chunk(
+            "local alive = setmetatable({}, {__mode = 'k'})
+             collectgarbage('stop')
+             for i = 1, 2000 do
+               local a, b = {}, {}
+               a.other, b.other = b, a
+               local f
+               f = function() return f end
+               alive[a], alive[f] = true, true
+             end
+             local stopped = not collectgarbage('isrunning')
+             collectgarbage()
+             return stopped, next(alive)",
+        );
+        assert_eq!(results, Ok(vec![Value::Boolean(true), Value::Nil]));
+    }
+
+    #[test]
+    fn unreachable_coroutines_are_collected() {
+        let results = run_chunk(
+            "local alive = setmetatable({}, {__mode = 'k'})
+             for i = 1, 3000 do
+               local gen
+               gen = coroutine.wrap(function()
+                 while true do coroutine.yield(gen) end
+               end)
+               gen()
+               alive[gen] = true
+               local co
+               co = coroutine.create(function() local me = co coroutine.yield(me) end)
+               coroutine.resume(co)
+               alive[co] = true
+             end
+             collectgarbage()
+             local count = 0
+             for _ in pairs(alive) do count = count + 1 end
+             return count",
+        );
+        assert_eq!(results, Ok(vec![Value::Integer(0)]));
+    }
+
+    #[test]
+    fn steps_run_the_collection_that_is_due() {
+        let results = run_chunk(
+            "local full = collectgarbage('step')
+             local previous = collectgarbage('generational')
+             local minor = collectgarbage('step')
+             return full, previous, minor, collectgarbage('incremental', 150, 100, 13)",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Boolean(true),
+                Value::String("incremental".to_string()),
+                Value::Boolean(false),
+                Value::String("generational".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn count_follows_the_objects_in_use() {
+        let results = run_chunk(
+            "local before = collectgarbage('count')
+             local t = {}
+             for i = 1, 1000 do t[i] = {i} end
+             local during = collectgarbage('count')
+             t = nil
+             collectgarbage()
+             local after = collectgarbage('count')
+             return during - before > 10, after < during",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![Value::Boolean(true), Value::Boolean(true)])
+        );
+    }
+
+    #[test]
+    fn suspended_coroutines_keep_their_locals() {
+        let results = run_chunk(
+            "local co = coroutine.create(function(...)
+               local t = {}
+               t.self = t
+               coroutine.yield()
+               return t.self == t, #{...}
+             end)
+             coroutine.resume(co, 1, 2, 3)
+             collectgarbage()
+             return coroutine.resume(co)",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Boolean(true),
+                Value::Boolean(true),
+                Value::Integer(3),
+            ])
+        );
+    }
+    #[test]
+    fn weak_values_do_not_keep_their_targets() {
+        let results = run_chunk(
+            "local cache = setmetatable({}, {__mode = 'v'})
+             local kept = {}
+             cache[1] = kept
+             cache[2] = {}
+             cache.name = 'strings are values, not objects'
+             collectgarbage()
+             return cache[1] == kept, cache[2], cache.name",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Boolean(true),
+                Value::Nil,
+                Value::String("strings are values, not objects".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn weak_keys_drop_entries_of_dead_keys() {
+        let results = run_chunk(
+            "local meta = setmetatable({}, {__mode = 'k'})
+             local both = setmetatable({}, {__mode = 'kv'})
+             local kept = {}
+             meta[kept] = 'kept'
+             meta[{}] = 'dropped'
+             both[kept] = {}
+             both[1] = kept
+             collectgarbage()
+             local count = 0
+             for _ in pairs(meta) do count = count + 1 end
+             return count, meta[kept], both[kept], both[1] == kept",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Integer(1),
+                Value::String("kept".to_string()),
+                Value::Nil,
+                Value::Boolean(true),
+            ])
+        );
+    }
+
+    #[test]
+    fn weak_keyed_tables_are_ephemerons() {
+        let results = run_chunk(
+            "local map = setmetatable({}, {__mode = 'k'})
+             local root = {}
+             -- Each value refers to the next key, and only the first key is
+             -- reachable from outside
+             local a, b, c = {}, {}, {}
+             map[a] = b
+             map[b] = c
+             map[c] = {}
+             -- A value referring to its own key does not keep it alive
+             local lonely = {}
+             map[lonely] = {owner = lonely}
+             root.first = a
+             a, b, c, lonely = nil, nil, nil, nil
+             collectgarbage()
+             local count = 0
+             for _ in pairs(map) do count = count + 1 end
+             return count",
+        );
+        assert_eq!(results, Ok(vec![Value::Integer(3)]));
+    }
+}
@@ -1,12 +1,15 @@
 use std::collections::HashMap;
+use std::fmt;
+
+use crate::parser::ParseError;
+use crate::value::{parse_number, Value};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     // Literals
+    Integer(i64),
     Number(f64),
     String(String),
-    Boolean(bool),
-    Nil,
 
     // Identifiers
     Identifier(String),
@@ -24,6 +27,7 @@ pub enum Token {
     If,
     In,
     Local,
+    Nil,
     Not,
     Or,
     Repeat,
@@ -38,9 +42,15 @@ pub enum Token {
     Minus,
     Multiply,
     Divide,
+    FloorDivide,
     Modulo,
     Power,
     Length,
+    BitwiseAnd,
+    BitwiseOr,
+    Tilde,
+    ShiftLeft,
+    ShiftRight,
     Equal,
     NotEqual,
     LessThan,
@@ -64,18 +74,86 @@ pub enum Token {
     Ellipsis,
 
     // Special
-    EOF,
+    Eof,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Token::Integer(n) => return write!(f, "{}", n),
+            Token::Number(n) => return write!(f, "{}", Value::Number(*n)),
+            Token::String(s) => return write!(f, "{}", s),
+            Token::Identifier(name) => return write!(f, "{}", name),
+            Token::And => "and",
+            Token::Break => "break",
+            Token::Do => "do",
+            Token::Else => "else",
+            Token::ElseIf => "elseif",
+            Token::End => "end",
+            Token::False => "false",
+            Token::For => "for",
+            Token::Function => "function",
+            Token::If => "if",
+            Token::In => "in",
+            Token::Local => "local",
+            Token::Nil => "nil",
+            Token::Not => "not",
+            Token::Or => "or",
+            Token::Repeat => "repeat",
+            Token::Return => "return",
+            Token::Then => "then",
+            Token::True => "true",
+            Token::Until => "until",
+            Token::While => "while",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Multiply => "*",
+            Token::Divide => "/",
+            Token::FloorDivide => "//",
+            Token::Modulo => "%",
+            Token::Power => "^",
+            Token::Length => "#",
+            Token::BitwiseAnd => "&",
+            Token::BitwiseOr => "|",
+            Token::Tilde => "~",
+            Token::ShiftLeft => "<<",
+            Token::ShiftRight => ">>",
+            Token::Equal => "==",
+            Token::NotEqual => "~=",
+            Token::LessThan => "<",
+            Token::LessEqual => "<=",
+            Token::GreaterThan => ">",
+            Token::GreaterEqual => ">=",
+            Token::Assign => "=",
+            Token::LeftParen => "(",
+            Token::RightParen => ")",
+            Token::LeftBrace => "{",
+            Token::RightBrace => "}",
+            Token::LeftBracket => "[",
+            Token::RightBracket => "]",
+            Token::Semicolon => ";",
+            Token::Comma => ",",
+            Token::Dot => ".",
+            Token::Colon => ":",
+            Token::DoubleDot => "..",
+            Token::Ellipsis => "...",
+            Token::Eof => "<eof>",
+        };
+        write!(f, "{}", text)
+    }
 }
 
 pub struct Lexer {
     source: String,
+    /// Name of the chunk being read, which prefixes error messages.
+    chunk_name: String,
     position: usize,
     line: usize,
     keywords: HashMap<String, Token>,
 }
 
 impl Lexer {
-    pub fn new(source: String) -> Self {
+    pub fn new(source: String, chunk_name: &str) -> Self {
         let mut keywords = HashMap::new();
         keywords.insert("and".to_string(), Token::And);
         keywords.insert("break".to_string(), Token::Break);
@@ -89,6 +167,7 @@ impl Lexer {
         keywords.insert("if".to_string(), Token::If);
         keywords.insert("in".to_string(), Token::In);
         keywords.insert("local".to_string(), Token::Local);
+        keywords.insert("nil".to_string(), Token::Nil);
         keywords.insert("not".to_string(), Token::Not);
         keywords.insert("or".to_string(), Token::Or);
         keywords.insert("repeat".to_string(), Token::Repeat);
@@ -100,109 +179,125 @@ impl Lexer {
 
         Lexer {
             source,
+            chunk_name: chunk_name.to_string(),
             position: 0,
             line: 1,
             keywords,
         }
     }
 
-    pub fn tokenize(&mut self) -> Vec<Token> {
+    /// Splits the source into tokens, each paired with the line it is on.
+    pub fn tokenize(&mut self) -> Result<Vec<(Token, usize)>, ParseError> {
         let mut tokens = Vec::new();
-        while let Some(token) = self.next_token() {
-            if token == Token::EOF {
-                tokens.push(token);
-                break;
+        loop {
+            let token = self.next_token()?;
+            let is_end = token == Token::Eof;
+            tokens.push((token, self.line));
+            if is_end {
+                return Ok(tokens);
             }
-            tokens.push(token);
         }
-        tokens
     }
 
-    fn next_token(&mut self) -> Option<Token> {
+    fn next_token(&mut self) -> Result<Token, ParseError> {
         self.skip_whitespace();
 
         if self.is_at_end() {
-            return Some(Token::EOF);
+            return Ok(Token::Eof);
         }
 
         let c = self.advance();
 
-        match c {
-            '+' => Some(Token::Plus),
-            '-' => Some(Token::Minus),
-            '*' => Some(Token::Multiply),
-            '/' => Some(Token::Divide),
-            '%' => Some(Token::Modulo),
-            '^' => Some(Token::Power),
-            '#' => Some(Token::Length),
-            '(' => Some(Token::LeftParen),
-            ')' => Some(Token::RightParen),
-            '{' => Some(Token::LeftBrace),
-            '}' => Some(Token::RightBrace),
-            '[' => Some(Token::LeftBracket),
-            ']' => Some(Token::RightBracket),
-            ';' => Some(Token::Semicolon),
-            ',' => Some(Token::Comma),
+        let token = match c {
+            '+' => Token::Plus,
+            '-' => Token::Minus,
+            '*' => Token::Multiply,
+            '/' => {
+                if self.match_char('/') {
+                    Token::FloorDivide
+                } else {
+                    Token::Divide
+                }
+            }
+            '%' => Token::Modulo,
+            '^' => Token::Power,
+            '#' => Token::Length,
+            '&' => Token::BitwiseAnd,
+            '|' => Token::BitwiseOr,
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            '{' => Token::LeftBrace,
+            '}' => Token::RightBrace,
+            '[' => Token::LeftBracket,
+            ']' => Token::RightBracket,
+            ';' => Token::Semicolon,
+            ',' => Token::Comma,
             '=' => {
                 if self.match_char('=') {
-                    Some(Token::Equal)
+                    Token::Equal
                 } else {
-                    Some(Token::Assign)
+                    Token::Assign
                 }
             }
             '<' => {
                 if self.match_char('=') {
-                    Some(Token::LessEqual)
+                    Token::LessEqual
+                } else if self.match_char('<') {
+                    Token::ShiftLeft
                 } else {
-                    Some(Token::LessThan)
+                    Token::LessThan
                 }
             }
             '>' => {
                 if self.match_char('=') {
-                    Some(Token::GreaterEqual)
+                    Token::GreaterEqual
+                } else if self.match_char('>') {
+                    Token::ShiftRight
                 } else {
-                    Some(Token::GreaterThan)
+                    Token::GreaterThan
                 }
             }
             '~' => {
                 if self.match_char('=') {
-                    Some(Token::NotEqual)
+                    Token::NotEqual
                 } else {
-                    None
+                    Token::Tilde
                 }
             }
             '.' => {
-                if self.match_char('.') {
+                if self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                    self.number(c)?
+                } else if self.match_char('.') {
                     if self.match_char('.') {
-                        Some(Token::Ellipsis)
+                        Token::Ellipsis
                     } else {
-                        Some(Token::DoubleDot)
+                        Token::DoubleDot
                     }
                 } else {
-                    Some(Token::Dot)
+                    Token::Dot
                 }
             }
-            ':' => Some(Token::Colon),
-            '"' => self.string(),
-            '\'' => self.string(),
+            ':' => Token::Colon,
+            '"' | '\'' => self.string(c)?,
             _ => {
                 if c.is_ascii_digit() {
-                    self.number(c)
+                    self.number(c)?
                 } else if c.is_ascii_alphabetic() || c == '_' {
                     self.identifier(c)
                 } else {
-                    None
+                    return Err(self.error("unexpected symbol", &c.to_string()));
                 }
             }
-        }
+        };
+        Ok(token)
     }
 
-    fn string(&mut self) -> Option<Token> {
+    fn string(&mut self, quote: char) -> Result<Token, ParseError> {
         let mut value = String::new();
         while let Some(c) = self.peek() {
-            if c == '"' || c == '\'' {
+            if c == quote {
                 self.advance();
-                return Some(Token::String(value));
+                return Ok(Token::String(value));
             }
             if c == '\\' {
                 self.advance();
@@ -223,23 +318,52 @@ impl Lexer {
                 self.advance();
             }
         }
-        None
+        Err(self.error("unfinished string", &format!("{}{}", quote, value)))
     }
 
-    fn number(&mut self, first: char) -> Option<Token> {
+    /// Reads a numeral. An integer numeral becomes an integer token unless
+    /// it overflows; one with a radix point or an exponent is a float.
+    fn number(&mut self, first: char) -> Result<Token, ParseError> {
         let mut value = first.to_string();
+        let is_hex = first == '0' && matches!(self.peek(), Some('x' | 'X'));
+        // Hexadecimal numerals mark their binary exponent with 'p'
+        let exponent_marks = if is_hex {
+            value.push(self.advance());
+            ['p', 'P']
+        } else {
+            ['e', 'E']
+        };
         while let Some(c) = self.peek() {
-            if c.is_ascii_digit() || c == '.' {
+            if exponent_marks.contains(&c) {
+                value.push(self.advance());
+                if let Some(sign @ ('+' | '-')) = self.peek() {
+                    value.push(sign);
+                    self.advance();
+                }
+            } else if c.is_ascii_digit() || c == '.' || is_hex && c.is_ascii_hexdigit() {
                 value.push(c);
                 self.advance();
             } else {
                 break;
             }
         }
-        value.parse().ok().map(Token::Number)
+        // A letter straight after a numeral makes the whole word malformed
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                value.push(c);
+                self.advance();
+            } else {
+                break;
+            }
+        }
+        match parse_number(&value) {
+            Some(Value::Integer(n)) => Ok(Token::Integer(n)),
+            Some(Value::Number(n)) => Ok(Token::Number(n)),
+            _ => Err(self.error("malformed number", &value)),
+        }
     }
 
-    fn identifier(&mut self, first: char) -> Option<Token> {
+    fn identifier(&mut self, first: char) -> Token {
         let mut value = first.to_string();
         while let Some(c) = self.peek() {
             if c.is_ascii_alphanumeric() || c == '_' {
@@ -251,9 +375,19 @@ impl Lexer {
         }
 
         if let Some(keyword) = self.keywords.get(&value) {
-            Some(keyword.clone())
+            keyword.clone()
         } else {
-            Some(Token::Identifier(value))
+            Token::Identifier(value)
+        }
+    }
+
+    /// Reports a malformed token the way the parser reports its errors.
+    fn error(&self, message: &str, near: &str) -> ParseError {
+        ParseError {
+            message: format!(
+                "{}:{}: {} near '{}'",
+                self.chunk_name, self.line, message, near
+            ),
         }
     }
 
@@ -316,3 +450,74 @@ impl Lexer {
         self.position >= self.source.len()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Lexer, Token};
+
+    fn tokens(source: &str) -> Result<Vec<Token>, String> {
+        let tokens = Lexer::new(source.to_string(), "test")
+            .tokenize()
+            .map_err(|e| e.to_string())?;
+        Ok(tokens.into_iter().map(|(token, _)| token).collect())
+    }
+
+    #[test]
+    fn numerals_keep_their_subtype() {
+        assert_eq!(
+            tokens("12 3.0 1e2 .5 0xff 0xA.8p1"),
+            Ok(vec![
+                Token::Integer(12),
+                Token::Number(3.0),
+                Token::Number(100.0),
+                Token::Number(0.5),
+                Token::Integer(255),
+                Token::Number(21.0),
+                Token::Eof,
+            ])
+        );
+    }
+
+    #[test]
+    fn malformed_numerals_are_errors() {
+        for (source, near) in [
+            ("x = 12abc", "12abc"),
+            ("3e", "3e"),
+            ("0x", "0x"),
+            ("1..2", "1..2"),
+        ] {
+            assert_eq!(
+                tokens(source),
+                Err(format!("test:1: malformed number near '{}'", near))
+            );
+        }
+    }
+
+    #[test]
+    fn unexpected_characters_are_errors() {
+        assert_eq!(
+            tokens("x = 5 @ 3"),
+            Err("test:1: unexpected symbol near '@'".to_string())
+        );
+        assert_eq!(
+            tokens("\n$"),
+            Err("test:2: unexpected symbol near '$'".to_string())
+        );
+    }
+
+    #[test]
+    fn strings_end_only_at_their_own_quote() {
+        assert_eq!(
+            tokens("'say \"hi\"' \"it's\""),
+            Ok(vec![
+                Token::String("say \"hi\"".to_string()),
+                Token::String("it's".to_string()),
+                Token::Eof,
+            ])
+        );
+        assert_eq!(
+            tokens("x = 'abc"),
+            Err("test:1: unfinished string near ''abc'".to_string())
+        );
+    }
+}
@@ -1,37 +1,95 @@
+mod coroutine;
+mod error;
+mod gc;
 mod lexer;
 mod parser;
+mod table;
 mod value;
 mod vm;
 
 use lexer::Lexer;
-use parser::Parser;
+use parser::{FunctionBody, ParseError, Parser};
 use std::io::{self, Write};
+use std::rc::Rc;
 use vm::Vm;
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
 
     if args.len() > 1 {
-        run_file(&args[1]);
+        run_file(&args[1], &args[2..]);
     } else {
         run_repl();
     }
 }
 
-fn run_file(filename: &str) {
+fn run_file(filename: &str, script_args: &[String]) {
     let source = std::fs::read_to_string(filename).unwrap_or_else(|_| {
         eprintln!("Error: Could not read file {}", filename);
         std::process::exit(1);
     });
 
-    let mut lexer = Lexer::new(source);
-    let tokens = lexer.tokenize();
+    let chunk = parse_source(source, filename).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
+
+    let mut vm = Vm::new();
+    let script_args = script_args
+        .iter()
+        .map(|arg| value::Value::String(arg.clone()))
+        .collect();
+    let result = vm.execute(chunk, script_args);
+    vm.close();
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn parse_source(source: String, chunk_name: &str) -> Result<Rc<FunctionBody>, ParseError> {
+    let mut lexer = Lexer::new(source, chunk_name);
+    let tokens = lexer.tokenize()?;
+
+    let mut parser = Parser::new(tokens, chunk_name);
+    parser.parse()
+}
 
-    let mut parser = Parser::new(tokens);
-    let stmts = parser.parse();
+/// Parses a line typed at the REPL, whose values get printed when it is
+/// just a list of expressions.
+fn parse_line(line: &str) -> Result<Rc<FunctionBody>, ParseError> {
+    let mut lexer = Lexer::new(line.to_string(), "stdin");
+    let tokens = lexer.tokenize()?;
 
+    let mut parser = Parser::new(tokens, "stdin");
+    parser.parse_interactive()
+}
+
+/// Runs a chunk in a fresh VM and returns its results, or the message of
+/// the error it raised. Tests use it to check programs end to end.
+#[cfg(test)]
+fn run_chunk(source: &str) -> Result<Vec<value::Value>, String> {
+    let chunk = parse_source(source.to_string(), "test").map_err(|e| e.to_string())?;
+    let mut vm = Vm::new();
+    let result = vm.execute(chunk, Vec::new());
+    vm.close();
+    result.map_err(|e| e.to_string())
+}
+
+/// Feeds lines to one VM the way the REPL does and collects what each line
+/// evaluates to.
+#[cfg(test)]
+fn run_lines(lines: &[&str]) -> Vec<Result<Vec<value::Value>, String>> {
     let mut vm = Vm::new();
-    vm.execute(stmts);
+    let results = lines
+        .iter()
+        .map(|line| {
+            let chunk = parse_line(line).map_err(|e| e.to_string())?;
+            vm.execute_interactive(chunk).map_err(|e| e.to_string())
+        })
+        .collect();
+    vm.close();
+    results
 }
 
 fn run_repl() {
@@ -45,7 +103,9 @@ fn run_repl() {
         io::stdout().flush().unwrap();
 
         let mut input = String::new();
-        io::stdin().read_line(&mut input).unwrap();
+        if io::stdin().read_line(&mut input).unwrap() == 0 {
+            break;
+        }
 
         let input = input.trim();
         if input == "exit" || input == "quit" {
@@ -56,15 +116,22 @@ fn run_repl() {
             continue;
         }
 
-        let mut lexer = Lexer::new(input.to_string());
-        let tokens = lexer.tokenize();
-
-        let mut parser = Parser::new(tokens);
-        let stmts = parser.parse();
-
-        let result = vm.execute(stmts);
-        if result != value::Value::Nil {
-            println!("{}", result);
+        let chunk = match parse_line(input) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                println!("Error: {}", e);
+                continue;
+            }
+        };
+
+        match vm.execute_interactive(chunk) {
+            Ok(results) if !results.is_empty() => {
+                let output: Vec<String> = results.iter().map(|v| v.to_string()).collect();
+                println!("{}", output.join("\t"));
+            }
+            Ok(_) => {}
+            Err(e) => println!("Error: {}", e),
         }
     }
+    vm.close();
 }
@@ -1,7 +1,10 @@
 use crate::lexer::Token;
+use std::collections::HashSet;
+use std::rc::Rc;
 
 #[derive(Debug, Clone)]
 pub enum Expr {
+    Integer(i64),
     Number(f64),
     String(String),
     Boolean(bool),
@@ -17,7 +20,12 @@ pub enum Expr {
         right: Box<Expr>,
     },
     FunctionCall {
-        name: String,
+        callee: Box<Expr>,
+        arguments: Vec<Expr>,
+    },
+    MethodCall {
+        object: Box<Expr>,
+        method: String,
         arguments: Vec<Expr>,
     },
     TableAccess {
@@ -27,6 +35,27 @@ pub enum Expr {
     TableConstructor {
         fields: Vec<TableField>,
     },
+    Function(Rc<FunctionBody>),
+    /// `...`, the extra arguments of a vararg function.
+    Vararg,
+    /// A parenthesised expression, which truncates a call to its first result.
+    Paren(Box<Expr>),
+}
+
+/// Parameters and statements of a function literal, shared by every
+/// closure created from it.
+#[derive(Debug)]
+pub struct FunctionBody {
+    pub parameters: Vec<String>,
+    /// Whether the parameter list ends in `...`.
+    pub is_vararg: bool,
+    pub body: Vec<Stmt>,
+    /// Name of the chunk the function was defined in, for error positions.
+    pub source: Rc<str>,
+    /// Names referenced in the body (or nested functions) that are not
+    /// parameters; closures capture whichever of these are locals in the
+    /// defining scope.
+    pub upvalues: Vec<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -34,6 +63,7 @@ pub enum UnaryOperator {
     Not,
     Minus,
     Length,
+    BitwiseNot,
 }
 
 #[derive(Debug, Clone)]
@@ -42,9 +72,15 @@ pub enum BinaryOperator {
     Subtract,
     Multiply,
     Divide,
+    FloorDivide,
     Modulo,
     Power,
     Concat,
+    BitwiseAnd,
+    BitwiseOr,
+    BitwiseXor,
+    ShiftLeft,
+    ShiftRight,
     Equal,
     NotEqual,
     LessThan,
@@ -55,22 +91,70 @@ pub enum BinaryOperator {
     Or,
 }
 
+impl BinaryOperator {
+    /// Left and right binding power, following Lua 5.4's priority table.
+    /// Right-associative operators bind less tightly on their right side.
+    fn precedence(&self) -> (u8, u8) {
+        match self {
+            BinaryOperator::Or => (1, 1),
+            BinaryOperator::And => (2, 2),
+            BinaryOperator::Equal
+            | BinaryOperator::NotEqual
+            | BinaryOperator::LessThan
+            | BinaryOperator::LessEqual
+            | BinaryOperator::GreaterThan
+            | BinaryOperator::GreaterEqual => (3, 3),
+            BinaryOperator::BitwiseOr => (4, 4),
+            BinaryOperator::BitwiseXor => (5, 5),
+            BinaryOperator::BitwiseAnd => (6, 6),
+            BinaryOperator::ShiftLeft | BinaryOperator::ShiftRight => (7, 7),
+            BinaryOperator::Concat => (9, 8),
+            BinaryOperator::Add | BinaryOperator::Subtract => (10, 10),
+            BinaryOperator::Multiply
+            | BinaryOperator::Divide
+            | BinaryOperator::FloorDivide
+            | BinaryOperator::Modulo => (11, 11),
+            BinaryOperator::Power => (14, 13),
+        }
+    }
+}
+
+/// Priority of unary operators: tighter than every binary operator except `^`.
+const UNARY_PRIORITY: u8 = 12;
+
 #[derive(Debug, Clone)]
 pub enum TableField {
     Value(Expr),
     KeyValue(String, Expr),
+    ComputedKey(Expr, Expr),
+}
+
+/// The target of an assignment: a variable or a table slot.
+#[derive(Debug, Clone)]
+pub enum LValue {
+    Name(String),
+    Index { table: Expr, key: Expr },
+}
+
+/// A statement and the line it starts on.
+#[derive(Debug, Clone)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub line: usize,
 }
 
 #[derive(Debug, Clone)]
-pub enum Stmt {
+pub enum StmtKind {
     Expr(Expr),
     Assignment {
-        variables: Vec<String>,
+        targets: Vec<LValue>,
         values: Vec<Expr>,
     },
     LocalAssignment {
         variables: Vec<String>,
         values: Vec<Expr>,
+        /// Position in `variables` of the to-be-closed variable, if any.
+        close: Option<usize>,
     },
     If {
         condition: Expr,
@@ -93,44 +177,153 @@ pub enum Stmt {
         step: Option<Expr>,
         body: Vec<Stmt>,
     },
-    Function {
-        name: String,
-        parameters: Vec<String>,
+    GenericFor {
+        variables: Vec<String>,
+        expressions: Vec<Expr>,
         body: Vec<Stmt>,
     },
     LocalFunction {
         name: String,
-        parameters: Vec<String>,
-        body: Vec<Stmt>,
+        function: Rc<FunctionBody>,
     },
+    Do(Vec<Stmt>),
     Return(Option<Vec<Expr>>),
     Break,
 }
 
+/// A syntax error, reported with its position and the offending token like
+/// the reference implementation does.
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub message: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+type ParseResult<T> = Result<T, ParseError>;
+
 pub struct Parser {
     tokens: Vec<Token>,
+    /// Line of each token in `tokens`.
+    lines: Vec<usize>,
+    /// Name of the chunk being parsed, which prefixes error messages.
+    source: Rc<str>,
     position: usize,
+    referenced_names: Vec<HashSet<String>>,
+    /// Number of loops enclosing the current position in the current
+    /// function, used to reject a `break` with nothing to break out of.
+    loop_depth: usize,
+    /// Whether `...` may be used in the function being parsed.
+    in_vararg_function: bool,
+    /// Locals in scope at the current position, innermost last, with
+    /// whether each is read-only (`<const>` or `<close>`).
+    locals: Vec<(String, bool)>,
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
+    pub fn new(tokens: Vec<(Token, usize)>, source: &str) -> Self {
+        let (tokens, lines) = tokens.into_iter().unzip();
         Parser {
             tokens,
+            lines,
+            source: source.into(),
             position: 0,
+            referenced_names: Vec::new(),
+            loop_depth: 0,
+            in_vararg_function: true,
+            locals: Vec::new(),
         }
     }
 
-    pub fn parse(&mut self) -> Vec<Stmt> {
-        let mut statements = Vec::new();
-        while !self.is_at_end() {
-            if let Some(stmt) = self.parse_statement() {
-                statements.push(stmt);
-            }
+    /// Parses a whole chunk. Like real Lua, the chunk becomes the body of an
+    /// anonymous vararg function that the VM calls to run it.
+    pub fn parse(&mut self) -> ParseResult<Rc<FunctionBody>> {
+        let statements = self.parse_block()?;
+        self.finish_chunk(statements)
+    }
+
+    /// Parses a line typed at the REPL. A line that is just a list of
+    /// expressions is read as `return` of them, so the REPL can print their
+    /// values; anything else parses like a chunk.
+    pub fn parse_interactive(&mut self) -> ParseResult<Rc<FunctionBody>> {
+        if !self.starts_expression() {
+            return self.parse();
         }
-        statements
+        let line = self.line();
+        let mut expressions = self.parse_expression_list()?;
+        let kind = if self.is_at_end() {
+            StmtKind::Return(Some(expressions))
+        } else if self.match_token(&[Token::Assign]) {
+            let targets = expressions
+                .into_iter()
+                .map(|target| self.to_lvalue(target))
+                .collect::<ParseResult<_>>()?;
+            let values = self.parse_expression_list()?;
+            StmtKind::Assignment { targets, values }
+        } else if expressions.len() == 1
+            && matches!(
+                expressions[0],
+                Expr::FunctionCall { .. } | Expr::MethodCall { .. }
+            )
+        {
+            StmtKind::Expr(expressions.remove(0))
+        } else {
+            return Err(self.error("syntax error"));
+        };
+        let mut statements = vec![Stmt { kind, line }];
+        statements.extend(self.parse_block()?);
+        self.finish_chunk(statements)
+    }
+
+    /// Whether the next token can start an expression. `function` only
+    /// starts one when no name follows it.
+    fn starts_expression(&self) -> bool {
+        match self.peek() {
+            Some(Token::Function) => self.peek_next() == Some(&Token::LeftParen),
+            Some(
+                Token::Identifier(_)
+                | Token::LeftParen
+                | Token::Integer(_)
+                | Token::Number(_)
+                | Token::String(_)
+                | Token::Nil
+                | Token::True
+                | Token::False
+                | Token::Ellipsis
+                | Token::LeftBrace
+                | Token::Minus
+                | Token::Not
+                | Token::Length
+                | Token::Tilde,
+            ) => true,
+            _ => false,
+        }
+    }
+
+    fn finish_chunk(&self, statements: Vec<Stmt>) -> ParseResult<Rc<FunctionBody>> {
+        if !self.is_at_end() {
+            return Err(self.error_expected("<eof>"));
+        }
+        Ok(Rc::new(FunctionBody {
+            parameters: Vec::new(),
+            is_vararg: true,
+            body: statements,
+            source: self.source.clone(),
+            upvalues: Vec::new(),
+        }))
+    }
+
+    fn parse_statement(&mut self) -> ParseResult<Stmt> {
+        let line = self.line();
+        let kind = self.parse_statement_kind()?;
+        Ok(Stmt { kind, line })
     }
 
-    fn parse_statement(&mut self) -> Option<Stmt> {
+    fn parse_statement_kind(&mut self) -> ParseResult<StmtKind> {
         if self.match_token(&[Token::If]) {
             self.parse_if()
         } else if self.match_token(&[Token::While]) {
@@ -139,151 +332,279 @@ impl Parser {
             self.parse_repeat()
         } else if self.match_token(&[Token::For]) {
             self.parse_for()
+        } else if self.match_token(&[Token::Do]) {
+            let body = self.parse_block()?;
+            self.consume(Token::End)?;
+            Ok(StmtKind::Do(body))
         } else if self.match_token(&[Token::Function]) {
             self.parse_function()
         } else if self.match_token(&[Token::Local]) {
             self.parse_local()
         } else if self.match_token(&[Token::Return]) {
             self.parse_return()
-        } else if self.match_token(&[Token::Break]) {
+        } else if self.check(&Token::Break) {
+            if self.loop_depth == 0 {
+                return Err(self.error("break outside a loop"));
+            }
             self.advance();
-            Some(Stmt::Break)
+            Ok(StmtKind::Break)
         } else {
             let expr = self.parse_expression()?;
-            if self.match_token(&[Token::Assign]) {
+            if self.check(&Token::Assign) || self.check(&Token::Comma) {
                 self.parse_assignment(expr)
+            } else if matches!(expr, Expr::FunctionCall { .. } | Expr::MethodCall { .. }) {
+                Ok(StmtKind::Expr(expr))
             } else {
-                Some(Stmt::Expr(expr))
+                Err(self.error("syntax error"))
             }
         }
     }
 
-    fn parse_assignment(&mut self, first: Expr) -> Option<Stmt> {
-        let mut variables = vec![match first {
-            Expr::Identifier(name) => name,
-            _ => return None,
-        }];
-
+    fn parse_assignment(&mut self, first: Expr) -> ParseResult<StmtKind> {
+        let mut targets = vec![self.to_lvalue(first)?];
         while self.match_token(&[Token::Comma]) {
-            if let Expr::Identifier(name) = self.parse_expression()? {
-                variables.push(name);
-            } else {
-                return None;
-            }
+            let target = self.parse_suffixed()?;
+            targets.push(self.to_lvalue(target)?);
         }
 
-        self.consume(Token::Assign);
+        self.consume(Token::Assign)?;
+        let values = self.parse_expression_list()?;
+        Ok(StmtKind::Assignment { targets, values })
+    }
 
-        let mut values = Vec::new();
-        loop {
-            values.push(self.parse_expression()?);
-            if !self.match_token(&[Token::Comma]) {
-                break;
+    fn to_lvalue(&self, expr: Expr) -> ParseResult<LValue> {
+        match expr {
+            Expr::Identifier(name) => {
+                self.check_assignable(&name)?;
+                Ok(LValue::Name(name))
             }
+            Expr::TableAccess { table, key } => Ok(LValue::Index {
+                table: *table,
+                key: *key,
+            }),
+            _ => Err(self.error("syntax error")),
         }
-
-        Some(Stmt::Assignment { variables, values })
     }
 
-    fn parse_local(&mut self) -> Option<Stmt> {
+    fn parse_local(&mut self) -> ParseResult<StmtKind> {
         if self.match_token(&[Token::Function]) {
-            let name = match self.advance()? {
-                Token::Identifier(name) => name,
-                _ => return None,
-            };
-            self.consume(Token::LeftParen);
-            let parameters = self.parse_parameters()?;
-            self.consume(Token::RightParen);
-            let body = self.parse_block()?;
-            self.consume(Token::End);
-            Some(Stmt::LocalFunction {
-                name,
-                parameters,
-                body,
-            })
+            let name = self.parse_name()?;
+            // In scope in its own body, so the function can recurse
+            self.locals.push((name.clone(), false));
+            let function = self.parse_function_body(false)?;
+            Ok(StmtKind::LocalFunction { name, function })
         } else {
             let mut variables = Vec::new();
+            let mut read_only = Vec::new();
+            let mut close = None;
             loop {
-                if let Token::Identifier(name) = self.advance()? {
-                    variables.push(name);
-                } else {
-                    return None;
+                variables.push(self.parse_name()?);
+                let attribute = self.parse_attribute()?;
+                if attribute.as_deref() == Some("close") {
+                    if close.is_some() {
+                        return Err(
+                            self.semantic_error("multiple to-be-closed variables in local list")
+                        );
+                    }
+                    close = Some(variables.len() - 1);
                 }
+                read_only.push(attribute.is_some());
                 if !self.match_token(&[Token::Comma]) {
                     break;
                 }
             }
 
-            let mut values = Vec::new();
-            if self.match_token(&[Token::Assign]) {
-                loop {
-                    values.push(self.parse_expression()?);
-                    if !self.match_token(&[Token::Comma]) {
-                        break;
-                    }
-                }
+            let values = if self.match_token(&[Token::Assign]) {
+                self.parse_expression_list()?
+            } else {
+                Vec::new()
+            };
+
+            // The new locals are only in scope after the statement
+            self.locals
+                .extend(variables.iter().cloned().zip(read_only));
+            Ok(StmtKind::LocalAssignment {
+                variables,
+                values,
+                close,
+            })
+        }
+    }
+
+    /// Parses the optional `<const>` or `<close>` after the name of a
+    /// local.
+    fn parse_attribute(&mut self) -> ParseResult<Option<String>> {
+        if !self.match_token(&[Token::LessThan]) {
+            return Ok(None);
+        }
+        let attribute = self.parse_name()?;
+        if attribute != "const" && attribute != "close" {
+            return Err(self.semantic_error(&format!("unknown attribute '{}'", attribute)));
+        }
+        self.consume(Token::GreaterThan)?;
+        Ok(Some(attribute))
+    }
+
+    /// Rejects assignments to a read-only local.
+    fn check_assignable(&self, name: &str) -> ParseResult<()> {
+        match self.locals.iter().rev().find(|(local, _)| local == name) {
+            Some((_, true)) => {
+                Err(self.semantic_error(&format!("attempt to assign to const variable '{}'", name)))
             }
+            _ => Ok(()),
+        }
+    }
 
-            Some(Stmt::LocalAssignment { variables, values })
+    /// Parses `function a.b.c:m() ... end`, sugar for assigning a function
+    /// literal to the named variable or field. A method name after `:`
+    /// gives the function an implicit leading `self` parameter.
+    fn parse_function(&mut self) -> ParseResult<StmtKind> {
+        let name = self.parse_name()?;
+        self.note_reference(&name);
+        if !self.check(&Token::Dot) && !self.check(&Token::Colon) {
+            self.check_assignable(&name)?;
+        }
+        let mut target = LValue::Name(name);
+        let mut is_method = false;
+        while self.check(&Token::Dot) || self.check(&Token::Colon) {
+            is_method = self.match_token(&[Token::Colon]);
+            if !is_method {
+                self.advance();
+            }
+            let key = Expr::String(self.parse_name()?);
+            let table = match target {
+                LValue::Name(name) => Expr::Identifier(name),
+                LValue::Index { table, key } => Expr::TableAccess {
+                    table: Box::new(table),
+                    key: Box::new(key),
+                },
+            };
+            target = LValue::Index { table, key };
+            if is_method {
+                break;
+            }
         }
+
+        let function = self.parse_function_body(is_method)?;
+        Ok(StmtKind::Assignment {
+            targets: vec![target],
+            values: vec![Expr::Function(function)],
+        })
     }
 
-    fn parse_function(&mut self) -> Option<Stmt> {
-        let name = match self.advance()? {
-            Token::Identifier(name) => name,
-            _ => return None,
-        };
-        self.consume(Token::LeftParen);
-        let parameters = self.parse_parameters()?;
-        self.consume(Token::RightParen);
-        let body = self.parse_block()?;
-        self.consume(Token::End);
-        Some(Stmt::Function {
-            name,
+    fn parse_function_body(&mut self, is_method: bool) -> ParseResult<Rc<FunctionBody>> {
+        self.consume(Token::LeftParen)?;
+        let (mut parameters, is_vararg) = self.parse_parameters()?;
+        if is_method {
+            parameters.insert(0, "self".to_string());
+        }
+        self.consume(Token::RightParen)?;
+        self.referenced_names.push(HashSet::new());
+        let enclosing_loop_depth = std::mem::replace(&mut self.loop_depth, 0);
+        let enclosing_vararg = std::mem::replace(&mut self.in_vararg_function, is_vararg);
+        let scope = self.locals.len();
+        self.locals
+            .extend(parameters.iter().map(|name| (name.clone(), false)));
+        let body = self.parse_block();
+        self.locals.truncate(scope);
+        self.loop_depth = enclosing_loop_depth;
+        self.in_vararg_function = enclosing_vararg;
+        let referenced = self.referenced_names.pop().unwrap_or_default();
+        let body = body?;
+        self.consume(Token::End)?;
+
+        let upvalues: Vec<String> = referenced
+            .into_iter()
+            .filter(|name| !parameters.contains(name))
+            .collect();
+        // Whatever this function captures must be available to capture in
+        // the enclosing function too.
+        if let Some(enclosing) = self.referenced_names.last_mut() {
+            enclosing.extend(upvalues.iter().cloned());
+        }
+
+        Ok(Rc::new(FunctionBody {
             parameters,
+            is_vararg,
             body,
-        })
+            source: self.source.clone(),
+            upvalues,
+        }))
     }
 
-    fn parse_parameters(&mut self) -> Option<Vec<String>> {
+    /// Parses a parameter list, returning the names and whether it ends
+    /// with `...`.
+    fn parse_parameters(&mut self) -> ParseResult<(Vec<String>, bool)> {
         let mut parameters = Vec::new();
         if self.check(&Token::RightParen) {
-            return Some(parameters);
+            return Ok((parameters, false));
         }
 
         loop {
-            if let Token::Identifier(name) = self.advance()? {
-                parameters.push(name);
-            } else {
-                return None;
+            if self.match_token(&[Token::Ellipsis]) {
+                return Ok((parameters, true));
             }
+            parameters.push(self.parse_name()?);
             if !self.match_token(&[Token::Comma]) {
                 break;
             }
         }
 
-        Some(parameters)
+        Ok((parameters, false))
+    }
+
+    fn parse_block(&mut self) -> ParseResult<Vec<Stmt>> {
+        let scope = self.locals.len();
+        let statements = self.parse_statements();
+        self.locals.truncate(scope);
+        statements
     }
 
-    fn parse_block(&mut self) -> Option<Vec<Stmt>> {
+    fn parse_statements(&mut self) -> ParseResult<Vec<Stmt>> {
         let mut statements = Vec::new();
-        while !self.check(&Token::End) && !self.check(&Token::Until) && !self.is_at_end() {
-            if let Some(stmt) = self.parse_statement() {
-                statements.push(stmt);
+        while !self.is_block_end() {
+            if self.match_token(&[Token::Semicolon]) {
+                continue;
+            }
+            let stmt = self.parse_statement()?;
+            let is_return = matches!(stmt.kind, StmtKind::Return(_));
+            statements.push(stmt);
+            // `return` can only be the last statement of a block
+            if is_return {
+                self.match_token(&[Token::Semicolon]);
+                if !self.is_block_end() {
+                    return Err(self.error_expected("end"));
+                }
             }
         }
-        Some(statements)
+        Ok(statements)
     }
 
-    fn parse_if(&mut self) -> Option<Stmt> {
+    /// Parses the body of a loop, where `break` is allowed.
+    fn parse_loop_body(&mut self) -> ParseResult<Vec<Stmt>> {
+        self.loop_depth += 1;
+        let body = self.parse_block();
+        self.loop_depth -= 1;
+        body
+    }
+
+    fn is_block_end(&self) -> bool {
+        self.is_at_end()
+            || self.check(&Token::End)
+            || self.check(&Token::Else)
+            || self.check(&Token::ElseIf)
+            || self.check(&Token::Until)
+    }
+
+    fn parse_if(&mut self) -> ParseResult<StmtKind> {
         let condition = self.parse_expression()?;
-        self.consume(Token::Then);
+        self.consume(Token::Then)?;
         let then_block = self.parse_block()?;
 
         let mut else_if_blocks = Vec::new();
         while self.match_token(&[Token::ElseIf]) {
             let condition = self.parse_expression()?;
-            self.consume(Token::Then);
+            self.consume(Token::Then)?;
             let block = self.parse_block()?;
             else_if_blocks.push((condition, block));
         }
@@ -294,8 +615,8 @@ impl Parser {
             None
         };
 
-        self.consume(Token::End);
-        Some(Stmt::If {
+        self.consume(Token::End)?;
+        Ok(StmtKind::If {
             condition,
             then_block,
             else_if_blocks,
@@ -303,39 +624,42 @@ impl Parser {
         })
     }
 
-    fn parse_while(&mut self) -> Option<Stmt> {
+    fn parse_while(&mut self) -> ParseResult<StmtKind> {
         let condition = self.parse_expression()?;
-        self.consume(Token::Do);
-        let body = self.parse_block()?;
-        self.consume(Token::End);
-        Some(Stmt::While { condition, body })
+        self.consume(Token::Do)?;
+        let body = self.parse_loop_body()?;
+        self.consume(Token::End)?;
+        Ok(StmtKind::While { condition, body })
     }
 
-    fn parse_repeat(&mut self) -> Option<Stmt> {
-        let body = self.parse_block()?;
-        self.consume(Token::Until);
+    fn parse_repeat(&mut self) -> ParseResult<StmtKind> {
+        let body = self.parse_loop_body()?;
+        self.consume(Token::Until)?;
         let condition = self.parse_expression()?;
-        Some(Stmt::Repeat { body, condition })
+        Ok(StmtKind::Repeat { body, condition })
     }
 
-    fn parse_for(&mut self) -> Option<Stmt> {
-        let variable = match self.advance()? {
-            Token::Identifier(name) => name,
-            _ => return None,
-        };
-        self.consume(Token::Assign);
+    fn parse_for(&mut self) -> ParseResult<StmtKind> {
+        let variable = self.parse_name()?;
+        if self.check(&Token::Comma) || self.check(&Token::In) {
+            return self.parse_generic_for(variable);
+        }
+        self.consume(Token::Assign)?;
         let start = self.parse_expression()?;
-        self.consume(Token::Comma);
+        self.consume(Token::Comma)?;
         let end = self.parse_expression()?;
         let step = if self.match_token(&[Token::Comma]) {
             Some(self.parse_expression()?)
         } else {
             None
         };
-        self.consume(Token::Do);
-        let body = self.parse_block()?;
-        self.consume(Token::End);
-        Some(Stmt::For {
+        self.consume(Token::Do)?;
+        self.locals.push((variable.clone(), false));
+        let body = self.parse_loop_body();
+        self.locals.pop();
+        let body = body?;
+        self.consume(Token::End)?;
+        Ok(StmtKind::For {
             variable,
             start,
             end,
@@ -344,11 +668,37 @@ impl Parser {
         })
     }
 
-    fn parse_return(&mut self) -> Option<Stmt> {
-        if self.check(&Token::End) {
-            return Some(Stmt::Return(None));
+    fn parse_generic_for(&mut self, first: String) -> ParseResult<StmtKind> {
+        let mut variables = vec![first];
+        while self.match_token(&[Token::Comma]) {
+            variables.push(self.parse_name()?);
+        }
+        self.consume(Token::In)?;
+        let expressions = self.parse_expression_list()?;
+        self.consume(Token::Do)?;
+        let scope = self.locals.len();
+        self.locals
+            .extend(variables.iter().map(|name| (name.clone(), false)));
+        let body = self.parse_loop_body();
+        self.locals.truncate(scope);
+        let body = body?;
+        self.consume(Token::End)?;
+        Ok(StmtKind::GenericFor {
+            variables,
+            expressions,
+            body,
+        })
+    }
+
+    fn parse_return(&mut self) -> ParseResult<StmtKind> {
+        if self.is_block_end() || self.check(&Token::Semicolon) {
+            return Ok(StmtKind::Return(None));
         }
 
+        Ok(StmtKind::Return(Some(self.parse_expression_list()?)))
+    }
+
+    fn parse_expression_list(&mut self) -> ParseResult<Vec<Expr>> {
         let mut values = Vec::new();
         loop {
             values.push(self.parse_expression()?);
@@ -356,19 +706,20 @@ impl Parser {
                 break;
             }
         }
-
-        Some(Stmt::Return(Some(values)))
+        Ok(values)
     }
 
-    fn parse_expression(&mut self) -> Option<Expr> {
-        self.parse_binary()
+    fn parse_expression(&mut self) -> ParseResult<Expr> {
+        self.parse_binary(0)
     }
 
-    fn parse_binary(&mut self) -> Option<Expr> {
+    /// Precedence climbing: parses operands and every binary operator whose
+    /// left binding power is greater than `limit`.
+    fn parse_binary(&mut self, limit: u8) -> ParseResult<Expr> {
         let mut left = self.parse_unary()?;
 
-        while let Some(op) = self.match_binary_op() {
-            let right = self.parse_unary()?;
+        while let Some((op, right_priority)) = self.match_binary_op(limit) {
+            let right = self.parse_binary(right_priority)?;
             left = Expr::BinaryOp {
                 left: Box::new(left),
                 operator: op,
@@ -376,13 +727,13 @@ impl Parser {
             };
         }
 
-        Some(left)
+        Ok(left)
     }
 
-    fn parse_unary(&mut self) -> Option<Expr> {
+    fn parse_unary(&mut self) -> ParseResult<Expr> {
         if let Some(op) = self.match_unary_op() {
-            let operand = Box::new(self.parse_unary()?);
-            Some(Expr::UnaryOp {
+            let operand = Box::new(self.parse_binary(UNARY_PRIORITY)?);
+            Ok(Expr::UnaryOp {
                 operator: op,
                 operand,
             })
@@ -391,84 +742,199 @@ impl Parser {
         }
     }
 
-    fn parse_primary(&mut self) -> Option<Expr> {
-        match self.advance()? {
-            Token::Number(n) => Some(Expr::Number(n)),
-            Token::String(s) => Some(Expr::String(s)),
-            Token::Boolean(b) => Some(Expr::Boolean(b)),
-            Token::Nil => Some(Expr::Nil),
-            Token::Identifier(name) => Some(Expr::Identifier(name)),
-            Token::LeftParen => {
+    fn parse_primary(&mut self) -> ParseResult<Expr> {
+        let expr = match self.peek() {
+            Some(Token::Integer(n)) => Expr::Integer(*n),
+            Some(Token::Number(n)) => Expr::Number(*n),
+            Some(Token::String(s)) => Expr::String(s.clone()),
+            Some(Token::True) => Expr::Boolean(true),
+            Some(Token::False) => Expr::Boolean(false),
+            Some(Token::Nil) => Expr::Nil,
+            Some(Token::Ellipsis) => {
+                if !self.in_vararg_function {
+                    return Err(self.error("cannot use '...' outside a vararg function"));
+                }
+                Expr::Vararg
+            }
+            Some(Token::LeftBrace) => {
+                self.advance();
+                return self.parse_table_constructor();
+            }
+            Some(Token::Function) => {
+                self.advance();
+                return Ok(Expr::Function(self.parse_function_body(false)?));
+            }
+            _ => return self.parse_suffixed(),
+        };
+        self.advance();
+        Ok(expr)
+    }
+
+    fn parse_prefix(&mut self) -> ParseResult<Expr> {
+        match self.peek() {
+            Some(Token::Identifier(name)) => {
+                let name = name.clone();
+                self.advance();
+                self.note_reference(&name);
+                Ok(Expr::Identifier(name))
+            }
+            Some(Token::LeftParen) => {
+                self.advance();
                 let expr = self.parse_expression()?;
-                self.consume(Token::RightParen);
-                Some(expr)
+                self.consume(Token::RightParen)?;
+                Ok(Expr::Paren(Box::new(expr)))
             }
-            _ => None,
+            _ => Err(self.error("unexpected symbol")),
         }
     }
 
-    fn match_binary_op(&mut self) -> Option<BinaryOperator> {
-        if let Some(token) = self.peek() {
-            match token {
-                Token::Plus => {
-                    self.advance();
-                    Some(BinaryOperator::Add)
-                }
-                Token::Minus => {
-                    self.advance();
-                    Some(BinaryOperator::Subtract)
-                }
-                Token::Multiply => {
-                    self.advance();
-                    Some(BinaryOperator::Multiply)
-                }
-                Token::Divide => {
-                    self.advance();
-                    Some(BinaryOperator::Divide)
-                }
-                Token::Power => {
-                    self.advance();
-                    Some(BinaryOperator::Power)
-                }
-                Token::Equal => {
+    fn parse_suffixed(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.parse_prefix()?;
+        loop {
+            match self.peek() {
+                Some(Token::Dot) => {
                     self.advance();
-                    Some(BinaryOperator::Equal)
+                    let name = self.parse_name()?;
+                    expr = Expr::TableAccess {
+                        table: Box::new(expr),
+                        key: Box::new(Expr::String(name)),
+                    };
                 }
-                Token::NotEqual => {
+                Some(Token::LeftBracket) => {
                     self.advance();
-                    Some(BinaryOperator::NotEqual)
+                    let key = self.parse_expression()?;
+                    self.consume(Token::RightBracket)?;
+                    expr = Expr::TableAccess {
+                        table: Box::new(expr),
+                        key: Box::new(key),
+                    };
                 }
-                Token::LessThan => {
+                Some(Token::Colon) => {
                     self.advance();
-                    Some(BinaryOperator::LessThan)
+                    let method = self.parse_name()?;
+                    let arguments = self.parse_call_arguments()?;
+                    expr = Expr::MethodCall {
+                        object: Box::new(expr),
+                        method,
+                        arguments,
+                    };
                 }
-                Token::LessEqual => {
-                    self.advance();
-                    Some(BinaryOperator::LessEqual)
+                Some(Token::LeftParen) | Some(Token::String(_)) | Some(Token::LeftBrace) => {
+                    let arguments = self.parse_call_arguments()?;
+                    expr = Expr::FunctionCall {
+                        callee: Box::new(expr),
+                        arguments,
+                    };
                 }
-                Token::GreaterThan => {
-                    self.advance();
-                    Some(BinaryOperator::GreaterThan)
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    fn parse_call_arguments(&mut self) -> ParseResult<Vec<Expr>> {
+        match self.peek() {
+            Some(Token::String(s)) => {
+                let s = s.clone();
+                self.advance();
+                Ok(vec![Expr::String(s)])
+            }
+            Some(Token::LeftBrace) => {
+                self.advance();
+                Ok(vec![self.parse_table_constructor()?])
+            }
+            Some(Token::LeftParen) => {
+                self.advance();
+                if self.match_token(&[Token::RightParen]) {
+                    return Ok(Vec::new());
                 }
-                Token::GreaterEqual => {
+                let arguments = self.parse_expression_list()?;
+                self.consume(Token::RightParen)?;
+                Ok(arguments)
+            }
+            _ => Err(self.error("function arguments expected")),
+        }
+    }
+
+    fn parse_table_constructor(&mut self) -> ParseResult<Expr> {
+        let mut fields = Vec::new();
+        while !self.check(&Token::RightBrace) {
+            let field = match (self.peek(), self.peek_next()) {
+                (Some(Token::LeftBracket), _) => {
                     self.advance();
-                    Some(BinaryOperator::GreaterEqual)
+                    let key = self.parse_expression()?;
+                    self.consume(Token::RightBracket)?;
+                    self.consume(Token::Assign)?;
+                    TableField::ComputedKey(key, self.parse_expression()?)
                 }
-                Token::And => {
+                (Some(Token::Identifier(name)), Some(Token::Assign)) => {
+                    let name = name.clone();
                     self.advance();
-                    Some(BinaryOperator::And)
-                }
-                Token::Or => {
                     self.advance();
-                    Some(BinaryOperator::Or)
+                    TableField::KeyValue(name, self.parse_expression()?)
                 }
-                _ => None,
+                _ => TableField::Value(self.parse_expression()?),
+            };
+            fields.push(field);
+            if !self.match_token(&[Token::Comma, Token::Semicolon]) {
+                break;
             }
-        } else {
-            None
+        }
+        self.consume(Token::RightBrace)?;
+        Ok(Expr::TableConstructor { fields })
+    }
+
+    /// Records a use of a variable so enclosing closures capture it.
+    fn note_reference(&mut self, name: &str) {
+        if let Some(referenced) = self.referenced_names.last_mut() {
+            referenced.insert(name.to_string());
         }
     }
 
+    fn parse_name(&mut self) -> ParseResult<String> {
+        match self.peek() {
+            Some(Token::Identifier(name)) => {
+                let name = name.clone();
+                self.advance();
+                Ok(name)
+            }
+            _ => Err(self.error_expected("<name>")),
+        }
+    }
+
+    fn match_binary_op(&mut self, limit: u8) -> Option<(BinaryOperator, u8)> {
+        let op = match self.peek()? {
+            Token::Plus => BinaryOperator::Add,
+            Token::Minus => BinaryOperator::Subtract,
+            Token::Multiply => BinaryOperator::Multiply,
+            Token::Divide => BinaryOperator::Divide,
+            Token::FloorDivide => BinaryOperator::FloorDivide,
+            Token::Modulo => BinaryOperator::Modulo,
+            Token::Power => BinaryOperator::Power,
+            Token::DoubleDot => BinaryOperator::Concat,
+            Token::BitwiseAnd => BinaryOperator::BitwiseAnd,
+            Token::BitwiseOr => BinaryOperator::BitwiseOr,
+            Token::Tilde => BinaryOperator::BitwiseXor,
+            Token::ShiftLeft => BinaryOperator::ShiftLeft,
+            Token::ShiftRight => BinaryOperator::ShiftRight,
+            Token::Equal => BinaryOperator::Equal,
+            Token::NotEqual => BinaryOperator::NotEqual,
+            Token::LessThan => BinaryOperator::LessThan,
+            Token::LessEqual => BinaryOperator::LessEqual,
+            Token::GreaterThan => BinaryOperator::GreaterThan,
+            Token::GreaterEqual => BinaryOperator::GreaterEqual,
+            Token::And => BinaryOperator::And,
+            Token::Or => BinaryOperator::Or,
+            _ => return None,
+        };
+
+        let (left_priority, right_priority) = op.precedence();
+        if left_priority <= limit {
+            return None;
+        }
+        self.advance();
+        Some((op, right_priority))
+    }
+
     fn match_unary_op(&mut self) -> Option<UnaryOperator> {
         if let Some(token) = self.peek() {
             match token {
@@ -484,6 +950,10 @@ impl Parser {
                     self.advance();
                     Some(UnaryOperator::Length)
                 }
+                Token::Tilde => {
+                    self.advance();
+                    Some(UnaryOperator::BitwiseNot)
+                }
                 _ => None,
             }
         } else {
@@ -517,14 +987,50 @@ impl Parser {
         Some(token)
     }
 
-    fn consume(&mut self, token: Token) {
+    fn consume(&mut self, token: Token) -> ParseResult<()> {
         if self.check(&token) {
             self.advance();
+            Ok(())
+        } else {
+            Err(self.error_expected(&token.to_string()))
+        }
+    }
+
+    fn error(&self, message: &str) -> ParseError {
+        let near = match self.peek() {
+            Some(token) => format!("'{}'", token),
+            None => "<eof>".to_string(),
+        };
+        self.semantic_error(&format!("{} near {}", message, near))
+    }
+
+    /// An error in code that is well formed, which the reference
+    /// implementation reports without the token it stopped at.
+    fn semantic_error(&self, message: &str) -> ParseError {
+        ParseError {
+            message: format!("{}:{}: {}", self.source, self.line(), message),
+        }
+    }
+
+    /// Line of the current token, or of the end of the chunk.
+    fn line(&self) -> usize {
+        let last = self.lines.len().saturating_sub(1);
+        self.lines
+            .get(self.position.min(last))
+            .copied()
+            .unwrap_or(1)
+    }
+
+    fn error_expected(&self, what: &str) -> ParseError {
+        if what.starts_with('<') {
+            self.error(&format!("{} expected", what))
+        } else {
+            self.error(&format!("'{}' expected", what))
         }
     }
 
     fn is_at_end(&self) -> bool {
-        self.position >= self.tokens.len() || self.tokens[self.position] == Token::EOF
+        self.position >= self.tokens.len() || self.tokens[self.position] == Token::Eof
     }
 
     fn peek(&self) -> Option<&Token> {
@@ -534,4 +1040,231 @@ impl Parser {
             Some(&self.tokens[self.position])
         }
     }
+
+    fn peek_next(&self) -> Option<&Token> {
+        self.tokens.get(self.position + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::value::Value;
+    use crate::{run_chunk, run_lines};
+
+    #[test]
+    fn suffixed_expressions_chain_to_any_depth() {
+        let results = run_chunk(
+            "local t = {x = {y = {10, 20, 30}}}
+             function t.x.get(self, i) return function(j) return self.y[i] + j end end
+             return t.x.y[2], t.x:get(3)(4), t['x'].y[1]",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Integer(20),
+                Value::Integer(34),
+                Value::Integer(10)
+            ])
+        );
+    }
+
+    #[test]
+    fn string_and_table_arguments_need_no_parentheses() {
+        let results = run_chunk(
+            "local function first(a) return a end
+             return first'single', first\"double\", first{7, 8}[2]",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::String("single".to_string()),
+                Value::String("double".to_string()),
+                Value::Integer(8),
+            ])
+        );
+    }
+
+    #[test]
+    fn method_calls_pass_the_receiver_as_self() {
+        let results = run_chunk(
+            "local counter = {n = 0}
+             function counter.add(self, k) self.n = self.n + k return self end
+             counter:add(2):add(3)
+             return counter.n",
+        );
+        assert_eq!(results, Ok(vec![Value::Integer(5)]));
+    }
+
+    #[test]
+    fn incomplete_suffixes_are_syntax_errors() {
+        assert_eq!(
+            run_chunk("f("),
+            Err("test:1: unexpected symbol near <eof>".to_string())
+        );
+        assert_eq!(
+            run_chunk("x = t."),
+            Err("test:1: <name> expected near <eof>".to_string())
+        );
+    }
+
+    #[test]
+    fn table_constructors_mix_positional_named_and_computed_keys() {
+        let results = run_chunk(
+            "local k = 'key'
+             local t = {1, 2; x = 3, [k] = 4, [1 + 2] = 5, }
+             return t[1], t[2], t[3], t.x, t.key, #t",
+        );
+        let expected = [1, 2, 5, 3, 4, 3].map(Value::Integer).to_vec();
+        assert_eq!(results, Ok(expected));
+    }
+
+    #[test]
+    fn only_a_trailing_call_in_a_constructor_expands() {
+        let results = run_chunk(
+            "local function three() return 7, 8, 9 end
+             return #{three()}, #{three(), three()}, #{(three())}, #{}",
+        );
+        assert_eq!(results, Ok([3, 4, 1, 0].map(Value::Integer).to_vec()));
+    }
+
+    #[test]
+    fn malformed_constructors_are_syntax_errors() {
+        assert_eq!(
+            run_chunk("local t = {1,,2}"),
+            Err("test:1: unexpected symbol near ','".to_string())
+        );
+        assert_eq!(
+            run_chunk("local t = {[1] 2}"),
+            Err("test:1: '=' expected near '2'".to_string())
+        );
+    }
+
+    #[test]
+    fn binary_operators_follow_lua_precedence() {
+        let results = run_chunk(
+            "return 1 + 2 * 3, 5 - 3 - 1, 1 << 2 + 1, 1 | 2 ~ 3 & 4, 1 + 1 == 2 and 3 < 4",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Integer(7),
+                Value::Integer(1),
+                Value::Integer(8),
+                Value::Integer(3),
+                Value::Boolean(true),
+            ])
+        );
+    }
+
+    #[test]
+    fn power_and_concatenation_are_right_associative() {
+        let results = run_chunk("return 2 ^ 3 ^ 2, -2 ^ 2, 1 .. 2 .. 3, not nil == true");
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Number(512.0),
+                Value::Number(-4.0),
+                Value::String("123".to_string()),
+                Value::Boolean(true),
+            ])
+        );
+    }
+
+    #[test]
+    fn interactive_expression_lines_return_their_values() {
+        let results = run_lines(&[
+            "x = 5",
+            "x, x + 1",
+            "local t = {}",
+            "t.a, t.b = 1, 2",
+            "t.a + t.b",
+        ]);
+        assert_eq!(
+            results,
+            vec![
+                Ok(vec![]),
+                Ok(vec![Value::Integer(5), Value::Integer(6)]),
+                Ok(vec![]),
+                Ok(vec![]),
+                Ok(vec![Value::Integer(3)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn interactive_statement_lines_parse_like_chunks() {
+        let results = run_lines(&["function g() return 4 end", "g()", "g() g()", "x +", "1 2"]);
+        assert_eq!(
+            results,
+            vec![
+                Ok(vec![]),
+                Ok(vec![Value::Integer(4)]),
+                Ok(vec![]),
+                Err("stdin:1: unexpected symbol near <eof>".to_string()),
+                Err("stdin:1: syntax error near '2'".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn function_names_can_be_dotted_and_methods_take_self() {
+        let results = run_chunk(
+            "local a = {b = {}}
+             function a.b.c(x) return x end
+             function a.b:get(x) return self == a.b, x end
+             return a.b.c(1), a.b:get(2)",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Integer(1),
+                Value::Boolean(true),
+                Value::Integer(2)
+            ])
+        );
+    }
+
+    #[test]
+    fn method_calls_evaluate_the_receiver_once() {
+        let results = run_chunk(
+            "local obj = {n = 1}
+             function obj:get() return self.n end
+             local calls = 0
+             local function find() calls = calls + 1 return obj end
+             return find():get(), calls",
+        );
+        assert_eq!(results, Ok(vec![Value::Integer(1), Value::Integer(1)]));
+    }
+
+    #[test]
+    fn lexical_errors_stop_the_whole_chunk() {
+        assert_eq!(
+            run_chunk("x = 5 @ 3"),
+            Err("test:1: unexpected symbol near '@'".to_string())
+        );
+    }
+
+    #[test]
+    fn local_attributes_are_checked_when_parsing() {
+        assert_eq!(
+            run_chunk("local x <const> = 1\nx = 2"),
+            Err("test:2: attempt to assign to const variable 'x'".to_string())
+        );
+        assert_eq!(
+            run_chunk("local f <close> = nil\nfunction g() f = 1 end"),
+            Err("test:2: attempt to assign to const variable 'f'".to_string())
+        );
+        assert_eq!(
+            run_chunk("local x <static> = 1"),
+            Err("test:1: unknown attribute 'static'".to_string())
+        );
+        assert_eq!(
+            run_chunk("local a <close>, b <close> = nil, nil"),
+            Err("test:1: multiple to-be-closed variables in local list".to_string())
+        );
+        assert_eq!(
+            run_chunk("local x <const> = 1\ndo local x = 2 x = 3 end\nreturn x"),
+            Ok(vec![Value::Integer(1)])
+        );
+    }
 }
@@ -0,0 +1,349 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::rc::Rc;
+
+use crate::gc;
+use crate::value::Value;
+
+/// A Lua table. The values of the keys 1 to n live in an array part;
+/// every other key goes to a hash part that keeps its entries in insertion
+/// order, so that traversal with `next` is stable while existing fields are
+/// assigned, including to nil.
+#[derive(Debug, Default)]
+pub struct LuaTable {
+    array: Vec<Value>,
+    /// Position in `entries` of each key of the hash part.
+    index: HashMap<Value, usize>,
+    /// Fields of the hash part in insertion order. A field set to nil keeps
+    /// its entry until the next rehash, so a traversal can resume from it.
+    entries: Vec<(Value, Value)>,
+    /// How many of `entries` hold nil.
+    removed: usize,
+    metatable: Option<Rc<RefCell<LuaTable>>>,
+}
+
+impl LuaTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A table whose array part already spans the keys 1 to `size`, as for
+    /// a constructor with that many positional fields. Nil fields among
+    /// them stay in the array part.
+    pub fn with_array_size(size: usize) -> Self {
+        LuaTable {
+            array: vec![Value::Nil; size],
+            ..Self::default()
+        }
+    }
+
+    pub fn metatable(&self) -> Option<Rc<RefCell<LuaTable>>> {
+        self.metatable.clone()
+    }
+
+    pub fn set_metatable(&mut self, metatable: Option<Rc<RefCell<LuaTable>>>) {
+        self.metatable = metatable;
+    }
+
+    pub fn get(&self, key: &Value) -> Value {
+        match key {
+            Value::Integer(n) => self.get_integer(*n),
+            Value::Number(_) => match key.clone().normalize_key() {
+                Value::Integer(n) => self.get_integer(n),
+                key => self.get_hash(&key),
+            },
+            _ => self.get_hash(key),
+        }
+    }
+
+    fn get_integer(&self, n: i64) -> Value {
+        match self.array_slot(n) {
+            Some(slot) => self.array[slot].clone(),
+            None => self.get_hash(&Value::Integer(n)),
+        }
+    }
+
+    fn get_hash(&self, key: &Value) -> Value {
+        self.index
+            .get(key)
+            .map_or(Value::Nil, |&i| self.entries[i].1.clone())
+    }
+
+    /// Position in the array part of an integer key, if it falls there.
+    fn array_slot(&self, n: i64) -> Option<usize> {
+        if n >= 1 && n as u64 <= self.array.len() as u64 {
+            Some(n as usize - 1)
+        } else {
+            None
+        }
+    }
+
+    /// Assigns a field; assigning nil removes it. Fails for the keys a
+    /// table cannot hold, nil and NaN.
+    pub fn set(&mut self, key: Value, value: Value) -> Result<(), &'static str> {
+        let key = match key {
+            Value::Nil => return Err("index is nil"),
+            Value::Number(n) if n.is_nan() => return Err("index is NaN"),
+            key => key.normalize_key(),
+        };
+
+        if let Value::Integer(n) = key {
+            if let Some(slot) = self.array_slot(n) {
+                self.array[slot] = value;
+                return Ok(());
+            }
+        }
+
+        if let Some(&i) = self.index.get(&key) {
+            let field = &mut self.entries[i].1;
+            match (*field == Value::Nil, value == Value::Nil) {
+                (false, true) => self.removed += 1,
+                (true, false) => self.removed -= 1,
+                _ => {}
+            }
+            *field = value;
+            return Ok(());
+        }
+
+        if value == Value::Nil {
+            return Ok(());
+        }
+        if key == Value::Integer(self.array.len() as i64 + 1) {
+            self.array.push(value);
+            self.migrate_to_array();
+        } else {
+            if self.removed > self.entries.len() / 2 {
+                self.rehash();
+            }
+            self.index.insert(key.clone(), self.entries.len());
+            self.entries.push((key, value));
+        }
+        Ok(())
+    }
+
+    /// Moves the keys that now continue the array part out of the hash part.
+    fn migrate_to_array(&mut self) {
+        while let Some(&i) = self
+            .index
+            .get(&Value::Integer(self.array.len() as i64 + 1))
+        {
+            let value = std::mem::replace(&mut self.entries[i].1, Value::Nil);
+            if value == Value::Nil {
+                break;
+            }
+            self.removed += 1;
+            self.array.push(value);
+        }
+    }
+
+    /// Drops the fields that were set to nil. Only done when a new key is
+    /// added, which is when the reference implementation rehashes too.
+    fn rehash(&mut self) {
+        // Keys migrated to the array part left a nil entry behind as well
+        self.entries.retain(|(_, value)| *value != Value::Nil);
+        self.index = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, (key, _))| (key.clone(), i))
+            .collect();
+        self.removed = 0;
+    }
+
+    /// Every key and value the table holds, including the keys of fields
+    /// set to nil that are still waiting for a rehash.
+    pub fn references(&self) -> impl Iterator<Item = &Value> {
+        self.array
+            .iter()
+            .chain(self.index.keys())
+            .chain(self.entries.iter().flat_map(|(key, value)| [key, value]))
+    }
+
+    /// Roughly how many bytes the parts of the table take, along with the
+    /// strings it holds.
+    pub fn allocated_size(&self) -> usize {
+        self.array.capacity() * size_of::<Value>()
+            + self.index.capacity() * size_of::<(Value, usize)>()
+            + self.entries.capacity() * size_of::<(Value, Value)>()
+            + self.references().map(gc::string_size).sum::<usize>()
+    }
+
+    pub fn array(&self) -> &[Value] {
+        &self.array
+    }
+
+    /// The fields of the hash part, along with the keys of removed fields.
+    pub fn hash_fields(&self) -> impl Iterator<Item = (&Value, &Value)> {
+        self.entries.iter().map(|(key, value)| (key, value))
+    }
+
+    /// Removes the fields `keep` rejects, as assigning nil to them would.
+    pub fn retain(&mut self, mut keep: impl FnMut(&Value, &Value) -> bool) {
+        for (i, value) in self.array.iter_mut().enumerate() {
+            if *value != Value::Nil && !keep(&Value::Integer(i as i64 + 1), value) {
+                *value = Value::Nil;
+            }
+        }
+        for (key, value) in &mut self.entries {
+            if *value != Value::Nil && !keep(key, value) {
+                *value = Value::Nil;
+                self.removed += 1;
+            }
+        }
+    }
+
+    /// A border of the table: an index whose value is non-nil and followed
+    /// by nil, or 0 when `t[1]` is nil. Sequences have exactly one border,
+    /// their length.
+    pub fn length(&self) -> i64 {
+        let n = self.array.len();
+        if n > 0 && self.array[n - 1] == Value::Nil {
+            // There is a border within the array part; `i` is always 0 or
+            // non-nil and `j` nil
+            let (mut i, mut j) = (0, n);
+            while j - i > 1 {
+                let m = (i + j) / 2;
+                if self.array[m - 1] == Value::Nil {
+                    j = m;
+                } else {
+                    i = m;
+                }
+            }
+            return i as i64;
+        }
+
+        // The array part is full, so the sequence may go on in the hash part
+        let mut i = n as i64;
+        let mut j = i + 1;
+        while self.get_integer(j) != Value::Nil {
+            i = j;
+            if j > i64::MAX / 2 {
+                // Pathological table; find the border the slow way
+                let mut k = 1;
+                while self.get_integer(k) != Value::Nil {
+                    k += 1;
+                }
+                return k - 1;
+            }
+            j *= 2;
+        }
+        while j - i > 1 {
+            let m = i + (j - i) / 2;
+            if self.get_integer(m) == Value::Nil {
+                j = m;
+            } else {
+                i = m;
+            }
+        }
+        i
+    }
+
+    /// The field that follows `key` in traversal order, the array part
+    /// first; nil starts a traversal. None means the traversal is over.
+    pub fn next(&self, key: &Value) -> Result<Option<(Value, Value)>, &'static str> {
+        let start = match key.clone().normalize_key() {
+            Value::Nil => 0,
+            Value::Integer(n) if self.array_slot(n).is_some() => n as usize,
+            key => match self.index.get(&key) {
+                Some(&i) => self.array.len() + i + 1,
+                None => return Err("invalid key to 'next'"),
+            },
+        };
+
+        let array_fields = self
+            .array
+            .iter()
+            .enumerate()
+            .skip(start)
+            .map(|(i, value)| (Value::Integer(i as i64 + 1), value));
+        let hash_fields = self
+            .entries
+            .iter()
+            .skip(start.saturating_sub(self.array.len()))
+            .map(|(key, value)| (key.clone(), value));
+        let mut fields = array_fields.chain(hash_fields);
+        Ok(fields
+            .find(|(_, value)| **value != Value::Nil)
+            .map(|(key, value)| (key, value.clone())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LuaTable;
+    use crate::value::Value;
+
+    fn keys(table: &LuaTable) -> Vec<Value> {
+        let mut keys = Vec::new();
+        let mut key = Value::Nil;
+        while let Some((next, _)) = table.next(&key).unwrap() {
+            keys.push(next.clone());
+            key = next;
+        }
+        keys
+    }
+
+    #[test]
+    fn sequences_grow_into_the_array_part() {
+        let mut table = LuaTable::new();
+        // Keys set out of order migrate once the gap before them is filled
+        table.set(Value::Integer(2), Value::Integer(20)).unwrap();
+        table.set(Value::Integer(3), Value::Integer(30)).unwrap();
+        table.set(Value::Integer(1), Value::Integer(10)).unwrap();
+        assert_eq!(table.array().len(), 3);
+        assert_eq!(table.length(), 3);
+        assert_eq!(table.get(&Value::Number(2.0)), Value::Integer(20));
+    }
+
+    #[test]
+    fn length_is_a_border() {
+        let mut table = LuaTable::with_array_size(4);
+        table.set(Value::Integer(1), Value::Integer(1)).unwrap();
+        table.set(Value::Integer(2), Value::Integer(2)).unwrap();
+        assert_eq!(table.length(), 2);
+
+        let mut table = LuaTable::new();
+        table.set(Value::Integer(5), Value::Integer(5)).unwrap();
+        assert_eq!(table.length(), 0);
+        table.set(Value::Integer(1), Value::Integer(1)).unwrap();
+        assert_eq!(table.length(), 1);
+    }
+
+    #[test]
+    fn traversal_survives_clearing_visited_fields() {
+        let mut table = LuaTable::new();
+        for (i, name) in ["a", "b", "c"].into_iter().enumerate() {
+            table
+                .set(Value::String(name.to_string()), Value::Integer(i as i64))
+                .unwrap();
+        }
+        table.set(Value::Integer(1), Value::Boolean(true)).unwrap();
+        let mut key = Value::Nil;
+        let mut visited = 0;
+        while let Some((next, _)) = table.next(&key).unwrap() {
+            table.set(next.clone(), Value::Nil).unwrap();
+            key = next;
+            visited += 1;
+        }
+        assert_eq!(visited, 4);
+        assert_eq!(keys(&table), Vec::new());
+    }
+
+    #[test]
+    fn nil_and_nan_keys_are_rejected() {
+        let mut table = LuaTable::new();
+        assert_eq!(
+            table.set(Value::Nil, Value::Integer(1)),
+            Err("index is nil")
+        );
+        assert_eq!(
+            table.set(Value::Number(f64::NAN), Value::Integer(1)),
+            Err("index is NaN")
+        );
+        assert_eq!(
+            table.next(&Value::String("missing".to_string())),
+            Err("invalid key to 'next'")
+        );
+    }
+}
@@ -1,34 +1,77 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt;
 use std::rc::Rc;
 
+use crate::coroutine::Coroutine;
+use crate::error::LuaResult;
+use crate::parser::FunctionBody;
+use crate::table::LuaTable;
 use crate::Vm;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone)]
 pub enum Value {
     Nil,
     Boolean(bool),
+    Integer(i64),
     Number(f64),
     String(String),
-    Table(Rc<std::cell::RefCell<HashMap<Value, Value>>>),
+    Table(Rc<std::cell::RefCell<LuaTable>>),
     Function(Function),
+    Thread(Rc<Coroutine>),
 }
 
 #[derive(Debug, Clone)]
 pub enum Function {
-    Native(fn(&mut Vm, Vec<Value>) -> Value),
+    /// A builtin; it returns every result, so it may produce several.
+    Native(fn(&mut Vm, Vec<Value>) -> LuaResult<Vec<Value>>),
+    /// A builtin bound to values it is called with ahead of its arguments.
+    NativeClosure {
+        function: fn(&mut Vm, Vec<Value>) -> LuaResult<Vec<Value>>,
+        upvalues: Rc<Vec<Value>>,
+    },
     UserDefined {
-        parameters: Vec<String>,
-        body: Vec<crate::parser::Stmt>,
-        closure: Rc<std::cell::RefCell<HashMap<String, Value>>>,
+        function: Rc<FunctionBody>,
+        closure: Rc<HashMap<String, Variable>>,
     },
 }
 
+/// Storage for a local variable, shared with every closure that captures it.
+pub type Variable = Rc<std::cell::RefCell<Value>>;
+
+impl Function {
+    /// The address identifying the function. Each evaluation of a function
+    /// expression creates a closure with its own captures, so a closure is
+    /// only ever identical to copies of itself.
+    fn address(&self) -> usize {
+        match self {
+            Function::Native(f) => *f as usize,
+            Function::NativeClosure { upvalues, .. } => Rc::as_ptr(upvalues) as usize,
+            Function::UserDefined { closure, .. } => Rc::as_ptr(closure) as usize,
+        }
+    }
+}
+
 impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        self.address() == other.address()
+    }
+}
+
+/// Tables and functions are compared by reference, everything else by
+/// value. Numbers of different subtypes are never equal here; table keys
+/// are normalised instead.
+impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (Function::Native(f1), Function::Native(f2)) => std::ptr::eq(f1, f2),
-            (Function::UserDefined { .. }, Function::UserDefined { .. }) => false, // User-defined functions are never equal
+            (Value::Nil, Value::Nil) => true,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Integer(a), Value::Integer(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Table(a), Value::Table(b)) => Rc::ptr_eq(a, b),
+            (Value::Function(a), Value::Function(b)) => a == b,
+            (Value::Thread(a), Value::Thread(b)) => Rc::ptr_eq(a, b),
             _ => false,
         }
     }
@@ -44,16 +87,30 @@ impl std::hash::Hash for Value {
                 1.hash(state);
                 b.hash(state);
             }
-            Value::Number(n) => {
+            Value::Integer(n) => {
                 2.hash(state);
+                n.hash(state);
+            }
+            Value::Number(n) => {
+                3.hash(state);
                 n.to_bits().hash(state);
             }
             Value::String(s) => {
-                3.hash(state);
+                4.hash(state);
                 s.hash(state);
             }
-            Value::Table(_) => 4.hash(state),
-            Value::Function(_) => 5.hash(state),
+            Value::Table(t) => {
+                5.hash(state);
+                Rc::as_ptr(t).hash(state);
+            }
+            Value::Function(f) => {
+                6.hash(state);
+                f.address().hash(state);
+            }
+            Value::Thread(co) => {
+                7.hash(state);
+                Rc::as_ptr(co).hash(state);
+            }
         }
     }
 }
@@ -63,67 +120,101 @@ impl fmt::Display for Value {
         match self {
             Value::Nil => write!(f, "nil"),
             Value::Boolean(b) => write!(f, "{}", b),
-            Value::Number(n) => write!(f, "{}", n),
-            Value::String(s) => write!(f, "\"{}\"", s),
-            Value::Table(_) => write!(f, "table"),
-            Value::Function(_) => write!(f, "function"),
+            Value::Integer(n) => write!(f, "{}", n),
+            Value::Number(n) => write!(f, "{}", format_float(*n)),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Table(t) => write!(f, "table: {:p}", Rc::as_ptr(t)),
+            Value::Function(func) => write!(f, "function: {:#x}", func.address()),
+            Value::Thread(co) => write!(f, "thread: {:p}", Rc::as_ptr(co)),
         }
     }
 }
 
 impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::Integer(_) | Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Table(_) => "table",
+            Value::Function(_) => "function",
+            Value::Thread(_) => "thread",
+        }
+    }
+
     pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+
+    /// Converts a number or a numeric string to a number, keeping the
+    /// integer or float subtype it has or is written with.
+    pub fn coerce_number(&self) -> Option<Value> {
         match self {
-            Value::Nil => false,
-            Value::Boolean(false) => false,
-            _ => true,
+            Value::Integer(_) | Value::Number(_) => Some(self.clone()),
+            Value::String(s) => parse_number(s),
+            _ => None,
         }
     }
 
     pub fn to_number(&self) -> Option<f64> {
-        match self {
-            Value::Number(n) => Some(*n),
-            Value::String(s) => s.parse().ok(),
-            Value::Boolean(true) => Some(1.0),
-            Value::Boolean(false) => Some(0.0),
+        match self.coerce_number()? {
+            Value::Integer(n) => Some(n as f64),
+            Value::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// Converts a number or a numeric string to an integer, provided it has
+    /// an exact integer representation.
+    pub fn to_integer(&self) -> Option<i64> {
+        match self.coerce_number()? {
+            Value::Integer(n) => Some(n),
+            Value::Number(n) => float_to_integer(n),
             _ => None,
         }
     }
 
-    pub fn to_string(&self) -> String {
+    /// Turns a float key with an exact integer value into that integer, so
+    /// `t[1]` and `t[1.0]` refer to the same slot.
+    pub fn normalize_key(self) -> Value {
         match self {
-            Value::Nil => "nil".to_string(),
-            Value::Boolean(b) => b.to_string(),
-            Value::Number(n) => n.to_string(),
-            Value::String(s) => s.clone(),
-            Value::Table(_) => "table".to_string(),
-            Value::Function(_) => "function".to_string(),
+            Value::Number(n) => float_to_integer(n).map_or(self, Value::Integer),
+            _ => self,
         }
     }
 
     pub fn new_table() -> Self {
-        Value::Table(Rc::new(std::cell::RefCell::new(HashMap::new())))
+        Value::Table(Rc::new(std::cell::RefCell::new(LuaTable::new())))
     }
 
-    pub fn add(&self, other: &Value) -> Value {
-        match (self.to_number(), other.to_number()) {
-            (Some(a), Some(b)) => Value::Number(a + b),
-            _ => Value::Nil,
+    /// Applies an arithmetic operator on integers when both operands are
+    /// integers and on floats otherwise.
+    fn arithmetic(
+        &self,
+        other: &Value,
+        integer_op: fn(i64, i64) -> i64,
+        float_op: fn(f64, f64) -> f64,
+    ) -> Value {
+        match (self.coerce_number(), other.coerce_number()) {
+            (Some(Value::Integer(a)), Some(Value::Integer(b))) => Value::Integer(integer_op(a, b)),
+            _ => match (self.to_number(), other.to_number()) {
+                (Some(a), Some(b)) => Value::Number(float_op(a, b)),
+                _ => Value::Nil,
+            },
         }
     }
 
+    pub fn add(&self, other: &Value) -> Value {
+        self.arithmetic(other, i64::wrapping_add, |a, b| a + b)
+    }
+
     pub fn subtract(&self, other: &Value) -> Value {
-        match (self.to_number(), other.to_number()) {
-            (Some(a), Some(b)) => Value::Number(a - b),
-            _ => Value::Nil,
-        }
+        self.arithmetic(other, i64::wrapping_sub, |a, b| a - b)
     }
 
     pub fn multiply(&self, other: &Value) -> Value {
-        match (self.to_number(), other.to_number()) {
-            (Some(a), Some(b)) => Value::Number(a * b),
-            _ => Value::Nil,
-        }
+        self.arithmetic(other, i64::wrapping_mul, |a, b| a * b)
     }
 
     pub fn divide(&self, other: &Value) -> Value {
@@ -140,64 +231,172 @@ impl Value {
         }
     }
 
+    /// Whether both operands are integers and the divisor is zero, which
+    /// leaves `//` and `%` without a result.
+    pub fn is_integer_division_by_zero(&self, other: &Value) -> bool {
+        matches!(
+            (self.coerce_number(), other.coerce_number()),
+            (Some(Value::Integer(_)), Some(Value::Integer(0)))
+        )
+    }
+
+    /// Division rounding the quotient towards minus infinity.
+    pub fn floor_divide(&self, other: &Value) -> Value {
+        if self.is_integer_division_by_zero(other) {
+            return Value::Nil;
+        }
+        self.arithmetic(
+            other,
+            |a, b| {
+                let q = a.wrapping_div(b);
+                if a.wrapping_rem(b) != 0 && (a ^ b) < 0 {
+                    q - 1
+                } else {
+                    q
+                }
+            },
+            |a, b| (a / b).floor(),
+        )
+    }
+
+    /// The remainder of a division that rounds the quotient towards minus
+    /// infinity, so the result has the sign of the divisor.
     pub fn modulo(&self, other: &Value) -> Value {
-        match (self.to_number(), other.to_number()) {
-            (Some(a), Some(b)) => Value::Number(a % b),
+        if self.is_integer_division_by_zero(other) {
+            return Value::Nil;
+        }
+        self.arithmetic(
+            other,
+            |a, b| {
+                let r = a.wrapping_rem(b);
+                if r != 0 && (r ^ b) < 0 {
+                    r + b
+                } else {
+                    r
+                }
+            },
+            |a, b| {
+                let r = a % b;
+                if r != 0.0 && (r < 0.0) != (b < 0.0) {
+                    r + b
+                } else {
+                    r
+                }
+            },
+        )
+    }
+
+    /// Applies a bitwise operator to two numbers, which must both have an
+    /// exact integer representation.
+    fn bitwise(&self, other: &Value, op: fn(i64, i64) -> i64) -> Value {
+        match (self.to_integer(), other.to_integer()) {
+            (Some(a), Some(b)) => Value::Integer(op(a, b)),
             _ => Value::Nil,
         }
     }
 
+    pub fn bitwise_and(&self, other: &Value) -> Value {
+        self.bitwise(other, |a, b| a & b)
+    }
+
+    pub fn bitwise_or(&self, other: &Value) -> Value {
+        self.bitwise(other, |a, b| a | b)
+    }
+
+    pub fn bitwise_xor(&self, other: &Value) -> Value {
+        self.bitwise(other, |a, b| a ^ b)
+    }
+
+    pub fn shift_left(&self, other: &Value) -> Value {
+        self.bitwise(other, shift_left)
+    }
+
+    pub fn shift_right(&self, other: &Value) -> Value {
+        self.bitwise(other, |a, b| shift_left(a, b.wrapping_neg()))
+    }
+
+    pub fn bitwise_not(&self) -> Value {
+        match self.to_integer() {
+            Some(n) => Value::Integer(!n),
+            None => Value::Nil,
+        }
+    }
+
+    /// Equality as the `==` operator sees it: numbers are equal when they
+    /// denote the same mathematical value, whatever their subtype.
     pub fn equal(&self, other: &Value) -> Value {
-        Value::Boolean(self == other)
+        Value::Boolean(self.raw_equal(other))
     }
 
-    pub fn not_equal(&self, other: &Value) -> Value {
-        Value::Boolean(self != other)
+    fn raw_equal(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Integer(i), Value::Number(f)) | (Value::Number(f), Value::Integer(i)) => {
+                float_to_integer(*f) == Some(*i)
+            }
+            _ => self == other,
+        }
     }
 
-    pub fn less_than(&self, other: &Value) -> Value {
-        match (self.to_number(), other.to_number()) {
-            (Some(a), Some(b)) => Value::Boolean(a < b),
-            _ => Value::Boolean(false),
+    /// Orders two numbers, mixing subtypes exactly, or two strings. Other
+    /// pairs of values are not ordered.
+    fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Integer(a), Value::Integer(b)) => Some(a.cmp(b)),
+            (Value::Number(a), Value::Number(b)) => a.partial_cmp(b),
+            (Value::Integer(a), Value::Number(b)) => compare_integer_float(*a, *b),
+            (Value::Number(a), Value::Integer(b)) => {
+                compare_integer_float(*b, *a).map(Ordering::reverse)
+            }
+            (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+            _ => None,
         }
     }
 
+    pub fn less_than(&self, other: &Value) -> Value {
+        Value::Boolean(self.compare(other) == Some(Ordering::Less))
+    }
+
     pub fn less_equal(&self, other: &Value) -> Value {
-        match (self.to_number(), other.to_number()) {
-            (Some(a), Some(b)) => Value::Boolean(a <= b),
-            _ => Value::Boolean(false),
-        }
+        Value::Boolean(matches!(
+            self.compare(other),
+            Some(Ordering::Less | Ordering::Equal)
+        ))
     }
 
     pub fn greater_than(&self, other: &Value) -> Value {
-        match (self.to_number(), other.to_number()) {
-            (Some(a), Some(b)) => Value::Boolean(a > b),
-            _ => Value::Boolean(false),
-        }
+        Value::Boolean(self.compare(other) == Some(Ordering::Greater))
     }
 
     pub fn greater_equal(&self, other: &Value) -> Value {
-        match (self.to_number(), other.to_number()) {
-            (Some(a), Some(b)) => Value::Boolean(a >= b),
-            _ => Value::Boolean(false),
-        }
+        Value::Boolean(matches!(
+            self.compare(other),
+            Some(Ordering::Greater | Ordering::Equal)
+        ))
     }
 
+    /// Joins strings and numbers; any other operand makes it fail with nil.
     pub fn concat(&self, other: &Value) -> Value {
-        Value::String(format!("{}{}", self.to_string(), other.to_string()))
+        match (self, other) {
+            (
+                Value::String(_) | Value::Integer(_) | Value::Number(_),
+                Value::String(_) | Value::Integer(_) | Value::Number(_),
+            ) => Value::String(format!("{}{}", self, other)),
+            _ => Value::Nil,
+        }
     }
 
     pub fn length(&self) -> Value {
         match self {
-            Value::String(s) => Value::Number(s.len() as f64),
-            Value::Table(t) => Value::Number(t.borrow().len() as f64),
+            Value::String(s) => Value::Integer(s.len() as i64),
+            Value::Table(t) => Value::Integer(t.borrow().length()),
             _ => Value::Nil,
         }
     }
 
     pub fn negate(&self) -> Value {
-        match self.to_number() {
-            Some(n) => Value::Number(-n),
+        match self.coerce_number() {
+            Some(Value::Integer(n)) => Value::Integer(n.wrapping_neg()),
+            Some(Value::Number(n)) => Value::Number(-n),
             _ => Value::Nil,
         }
     }
@@ -206,3 +405,339 @@ impl Value {
         Value::Boolean(!self.is_truthy())
     }
 }
+
+/// Converts a numeral to an integer or a float by the rules of the lexer,
+/// allowing a sign and surrounding whitespace. A decimal integer numeral
+/// that overflows denotes a float, a hexadecimal one wraps around.
+pub fn parse_number(text: &str) -> Option<Value> {
+    let text = text.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (negative, numeral) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    match numeral
+        .strip_prefix("0x")
+        .or_else(|| numeral.strip_prefix("0X"))
+    {
+        Some(hex) => parse_hex(hex, negative),
+        None => parse_decimal(numeral, negative),
+    }
+}
+
+/// Splits a numeral into the digits before and after the radix point and
+/// the exponent, checking each part is well formed.
+fn split_numeral(
+    numeral: &str,
+    exponent_marks: [char; 2],
+    is_digit: fn(&char) -> bool,
+) -> Option<(&str, Option<&str>, Option<i64>)> {
+    let (mantissa, exponent) = match numeral.find(exponent_marks) {
+        Some(i) => (&numeral[..i], Some(&numeral[i + 1..])),
+        None => (numeral, None),
+    };
+    let (whole, fraction) = match mantissa.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (mantissa, None),
+    };
+    let fraction_digits = fraction.unwrap_or("");
+    if whole.is_empty() && fraction_digits.is_empty()
+        || !whole.chars().all(|c| is_digit(&c))
+        || !fraction_digits.chars().all(|c| is_digit(&c))
+    {
+        return None;
+    }
+    let exponent = match exponent {
+        Some(exponent) => {
+            let digits = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+            if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            // Far beyond any representable magnitude, so clamping is exact
+            Some(exponent.parse::<i64>().unwrap_or(if exponent.starts_with('-') {
+                i64::MIN / 2
+            } else {
+                i64::MAX / 2
+            }))
+        }
+        None => None,
+    };
+    Some((whole, fraction, exponent))
+}
+
+fn parse_decimal(numeral: &str, negative: bool) -> Option<Value> {
+    let (whole, fraction, exponent) = split_numeral(numeral, ['e', 'E'], char::is_ascii_digit)?;
+    if fraction.is_none() && exponent.is_none() {
+        if let Ok(n) = whole.parse::<u64>() {
+            if negative && n <= i64::MIN.unsigned_abs() {
+                return Some(Value::Integer((n as i64).wrapping_neg()));
+            }
+            if !negative && n <= i64::MAX as u64 {
+                return Some(Value::Integer(n as i64));
+            }
+        }
+    }
+    let n: f64 = numeral.parse().ok()?;
+    Some(Value::Number(if negative { -n } else { n }))
+}
+
+fn parse_hex(numeral: &str, negative: bool) -> Option<Value> {
+    let (whole, fraction, exponent) =
+        split_numeral(numeral, ['p', 'P'], char::is_ascii_hexdigit)?;
+    let digits = whole.chars().chain(fraction.unwrap_or("").chars());
+    if fraction.is_none() && exponent.is_none() {
+        let n = digits.fold(0i64, |n, c| {
+            n.wrapping_mul(16)
+                .wrapping_add(c.to_digit(16).unwrap_or(0) as i64)
+        });
+        return Some(Value::Integer(if negative { n.wrapping_neg() } else { n }));
+    }
+    let mantissa = digits.fold(0.0, |n, c| n * 16.0 + c.to_digit(16).unwrap_or(0) as f64);
+    // Each fraction digit shifts the binary exponent by four
+    let scale = exponent.unwrap_or(0) - 4 * fraction.map_or(0, str::len) as i64;
+    let n = mantissa * 2f64.powf(scale as f64);
+    Some(Value::Number(if negative { -n } else { n }))
+}
+
+/// The integer a float is equal to, if it has an integral value within the
+/// integer range.
+pub fn float_to_integer(n: f64) -> Option<i64> {
+    // -2^63 is exact as a float; 2^63 is the first value out of range
+    if n.fract() == 0.0 && n >= i64::MIN as f64 && n < -(i64::MIN as f64) {
+        Some(n as i64)
+    } else {
+        None
+    }
+}
+
+/// Logical shift of the bits of `x`: left for a positive count, right for a
+/// negative one. Shifting by 64 or more positions clears every bit.
+fn shift_left(x: i64, count: i64) -> i64 {
+    if count <= -64 || count >= 64 {
+        0
+    } else if count < 0 {
+        ((x as u64) >> -count) as i64
+    } else {
+        ((x as u64) << count) as i64
+    }
+}
+
+fn compare_integer_float(i: i64, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
+        return None;
+    }
+    // The floor is exact as an i128, the conversion saturating for floats
+    // far outside the integer range
+    let floor = f.floor();
+    match (i as i128).cmp(&(floor as i128)) {
+        Ordering::Equal if f > floor => Some(Ordering::Less),
+        ordering => Some(ordering),
+    }
+}
+
+/// Formats a float like `%.14g`, adding ".0" when the result would
+/// otherwise read as an integer.
+fn format_float(n: f64) -> String {
+    if n.is_nan() {
+        return if n.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if n.is_infinite() {
+        return if n > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+
+    let scientific = format!("{:.13e}", n);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap_or((&scientific, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+    let text = if !(-4..14).contains(&exponent) {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!(
+            "{}e{}{:02}",
+            trim_fraction(mantissa),
+            sign,
+            exponent.abs()
+        )
+    } else {
+        let decimals = (13 - exponent) as usize;
+        trim_fraction(&format!("{:.*}", decimals, n)).to_string()
+    };
+
+    if text.chars().all(|c| c == '-' || c.is_ascii_digit()) {
+        text + ".0"
+    } else {
+        text
+    }
+}
+
+/// Drops trailing zeros after the radix point, and the point itself when
+/// nothing is left after it.
+fn trim_fraction(text: &str) -> &str {
+    if text.contains('.') {
+        text.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_number, Value};
+    use crate::run_chunk;
+
+    #[test]
+    fn integer_arithmetic_wraps_and_keeps_its_subtype() {
+        let max = Value::Integer(i64::MAX);
+        assert_eq!(max.add(&Value::Integer(1)), Value::Integer(i64::MIN));
+        assert_eq!(
+            Value::Integer(7).floor_divide(&Value::Integer(-2)),
+            Value::Integer(-4)
+        );
+        assert_eq!(
+            Value::Integer(-7).modulo(&Value::Integer(3)),
+            Value::Integer(2)
+        );
+        assert_eq!(
+            Value::Integer(3).add(&Value::Number(0.0)),
+            Value::Number(3.0)
+        );
+        assert_eq!(
+            Value::Integer(7).divide(&Value::Integer(2)),
+            Value::Number(3.5)
+        );
+        assert_eq!(
+            Value::Integer(2).power(&Value::Integer(2)),
+            Value::Number(4.0)
+        );
+    }
+
+    #[test]
+    fn numerals_parse_to_the_right_subtype() {
+        assert_eq!(parse_number(" 10 "), Some(Value::Integer(10)));
+        assert_eq!(
+            parse_number("9007199254740993"),
+            Some(Value::Integer(9007199254740993))
+        );
+        assert_eq!(parse_number("1e2"), Some(Value::Number(100.0)));
+        assert_eq!(parse_number("-0x10"), Some(Value::Integer(-16)));
+        // Too big for an integer, so it becomes a float
+        assert_eq!(
+            parse_number("9223372036854775808"),
+            Some(Value::Number(9.223372036854776e18))
+        );
+        assert_eq!(parse_number("1e"), None);
+    }
+
+    #[test]
+    fn integer_division_by_zero_is_an_error() {
+        assert_eq!(
+            run_chunk("return 1 // 0"),
+            Err("test:1: attempt to perform 'n//0'".to_string())
+        );
+        assert_eq!(
+            run_chunk("return 1 % 0"),
+            Err("test:1: attempt to perform 'n%0'".to_string())
+        );
+        assert_eq!(
+            run_chunk("return 1 // 0.0"),
+            Ok(vec![Value::Number(f64::INFINITY)])
+        );
+    }
+
+    #[test]
+    fn float_keys_with_an_integer_value_index_like_integers() {
+        let results = run_chunk(
+            "local t = {}
+             t[1.0] = 'a'
+             return t[1], math.type(1), math.type(1.0), math.type('1')",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::String("a".to_string()),
+                Value::String("integer".to_string()),
+                Value::String("float".to_string()),
+                Value::Nil,
+            ])
+        );
+    }
+
+    #[test]
+    fn bitwise_operators_follow_integer_rules() {
+        assert_eq!(
+            Value::Integer(5).bitwise_and(&Value::Integer(3)),
+            Value::Integer(1)
+        );
+        assert_eq!(
+            Value::Integer(5).bitwise_or(&Value::Integer(3)),
+            Value::Integer(7)
+        );
+        assert_eq!(
+            Value::Integer(5).bitwise_xor(&Value::Integer(3)),
+            Value::Integer(6)
+        );
+        assert_eq!(Value::Integer(0).bitwise_not(), Value::Integer(-1));
+        assert_eq!(
+            Value::Integer(1).shift_left(&Value::Integer(63)),
+            Value::Integer(i64::MIN)
+        );
+        assert_eq!(
+            Value::Integer(1).shift_left(&Value::Integer(64)),
+            Value::Integer(0)
+        );
+        assert_eq!(
+            Value::Integer(-1).shift_right(&Value::Integer(63)),
+            Value::Integer(1)
+        );
+        // Floats and strings with an exact integer value are converted
+        assert_eq!(
+            Value::Number(3.0).bitwise_and(&Value::Integer(1)),
+            Value::Integer(1)
+        );
+        assert_eq!(
+            Value::String("3".to_string()).bitwise_or(&Value::Integer(0)),
+            Value::Integer(3)
+        );
+    }
+
+    #[test]
+    fn bitwise_operators_reject_fractional_floats() {
+        assert_eq!(
+            run_chunk("return 1.5 & 1"),
+            Err("test:1: number has no integer representation".to_string())
+        );
+        assert_eq!(
+            run_chunk("return 7 // 2, 7.0 // 2"),
+            Ok(vec![Value::Integer(3), Value::Number(3.0)])
+        );
+    }
+
+    #[test]
+    fn tables_and_closures_are_equal_only_to_themselves() {
+        let results = run_chunk(
+            "local a, b = {}, {}
+             local function make() return function() end end
+             local f = make()
+             return a == a, a == b, f == f, f == make()",
+        );
+        assert_eq!(
+            results,
+            Ok([true, false, true, false].map(Value::Boolean).to_vec())
+        );
+    }
+
+    #[test]
+    fn tables_and_functions_work_as_keys() {
+        let results = run_chunk(
+            "local k1, k2 = {}, {}
+             local t = {[k1] = 1, [k2] = 2, [print] = 3}
+             return t[k1], t[k2], t[print], t[{}]",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Integer(1),
+                Value::Integer(2),
+                Value::Integer(3),
+                Value::Nil
+            ])
+        );
+    }
+}
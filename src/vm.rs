@@ -1,33 +1,161 @@
-use crate::parser::{BinaryOperator, Expr, Stmt, UnaryOperator};
-use crate::value::{Function, Value};
+use crate::coroutine::{Coroutine, CoroutineStatus, LuaYielder, Outcome};
+use crate::error::{LuaError, LuaResult};
+use crate::gc::Collector;
+use crate::parser::{
+    BinaryOperator, Expr, FunctionBody, LValue, Stmt, StmtKind, TableField, UnaryOperator,
+};
+use crate::table::LuaTable;
+use crate::value::{Function, Value, Variable};
+use corosensei::stack::{DefaultStack, Stack};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
-#[derive(Debug)]
-pub struct Vm {
+/// How many `__index` or `__newindex` tables an access may go through
+/// before it is considered a loop, as in the reference implementation.
+const MAX_META_CHAIN: usize = 2000;
+
+/// Size of the stack Lua code starts on, the main chunk and each
+/// coroutine alike, and of each segment it moves on to as calls nest
+/// deeper.
+pub const STACK_SEGMENT_SIZE: usize = 1024 * 1024;
+
+/// Stack left on a segment below which evaluation moves on to a new one.
+/// It covers what the interpreter does between two checks.
+const STACK_RESERVE: usize = 128 * 1024;
+
+/// How many segments the stack of a thread may grow to before calls fail
+/// with "stack overflow". Each Lua call recurses through the interpreter,
+/// so this is what keeps runaway recursion from exhausting memory.
+const MAX_STACK_SEGMENTS: usize = 256;
+
+/// The state Lua code runs with. Each coroutine has its own, sharing
+/// everything but the call stack with the main thread's; the lifetime is
+/// that of the yielder a coroutine suspends itself with.
+pub struct Vm<'y> {
     globals: Rc<RefCell<HashMap<String, Value>>>,
-    stack: Vec<Value>,
     call_stack: Vec<CallFrame>,
+    /// Top-level locals of interactive input, kept alive between lines.
+    session_locals: HashMap<String, Variable>,
+    /// The coroutines being run, each resumed by the one below it. The main
+    /// thread is always at the bottom.
+    coroutines: Rc<RefCell<Vec<Rc<Coroutine>>>>,
+    collector: Rc<RefCell<Collector>>,
+    /// How the coroutine this state belongs to yields; None on the main
+    /// thread, which cannot.
+    yielder: Option<&'y LuaYielder>,
+    /// Address below which the stack segment in use is nearly exhausted;
+    /// zero on a stack that does not grow, such as the caller's.
+    stack_limit: usize,
+    /// Stack segments in use, the first one included.
+    segments: usize,
+    /// The last segment left, kept for the next time one is needed.
+    spare_segment: Option<DefaultStack>,
+}
+
+/// How a statement finished, telling enclosing blocks and loops whether to
+/// carry on, leave the loop or unwind to the function call.
+#[derive(Debug)]
+enum ControlFlow {
+    Normal,
+    Break,
+    Return(Vec<Value>),
+}
+
+/// An assignment target whose table and key have already been evaluated.
+enum Place {
+    Local(Variable),
+    Upvalue(Variable),
+    Global(String),
+    Field { table: Value, key: Value },
+    Index { table: Value, key: Value },
 }
 
 #[derive(Debug)]
 pub struct CallFrame {
-    locals: Rc<RefCell<HashMap<String, Value>>>,
-    return_value: Option<Value>,
+    locals: HashMap<String, Variable>,
+    /// Variables captured by the running closure.
+    upvalues: Rc<HashMap<String, Variable>>,
+    /// Arguments passed beyond the declared parameters of a vararg function.
+    varargs: Vec<Value>,
+    /// Bindings hidden by locals of the blocks being executed, restored in
+    /// reverse order as each block ends.
+    shadowed: Vec<(String, Option<Variable>)>,
+    /// Values of the to-be-closed variables in scope, innermost last, each
+    /// with the position of its variable in `shadowed`.
+    to_close: Vec<(usize, Value)>,
+    /// Chunk the running function was defined in.
+    source: Rc<str>,
+    /// Line of the statement being executed, 0 before the first one.
+    line: usize,
+}
+
+impl CallFrame {
+    /// The variables in scope, and those hidden by locals of inner blocks.
+    pub fn variables(&self) -> impl Iterator<Item = &Variable> {
+        let hidden = self
+            .shadowed
+            .iter()
+            .filter_map(|(_, previous)| previous.as_ref());
+        self.locals.values().chain(hidden)
+    }
+
+    /// The variables captured by the running closure.
+    pub fn upvalues(&self) -> &Rc<HashMap<String, Variable>> {
+        &self.upvalues
+    }
+
+    /// The values the frame holds outside of variables.
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.varargs
+            .iter()
+            .chain(self.to_close.iter().map(|(_, value)| value))
+    }
 }
 
-impl Vm {
+impl<'y> Vm<'y> {
     pub fn new() -> Self {
         let mut vm = Vm {
             globals: Rc::new(RefCell::new(HashMap::new())),
-            stack: Vec::new(),
             call_stack: Vec::new(),
+            session_locals: HashMap::new(),
+            coroutines: Rc::new(RefCell::new(vec![Rc::new(Coroutine::main())])),
+            collector: Rc::new(RefCell::new(Collector::new())),
+            yielder: None,
+            stack_limit: 0,
+            segments: 0,
+            spare_segment: None,
         };
         vm.setup_builtins();
         vm
     }
 
+    /// State for a new coroutine to run with on `stack`, sharing the
+    /// globals, the collector and the stack of running coroutines with
+    /// this one.
+    pub fn new_thread(&self, stack: &DefaultStack) -> Vm<'static> {
+        Vm {
+            globals: self.globals.clone(),
+            call_stack: Vec::new(),
+            session_locals: HashMap::new(),
+            coroutines: self.coroutines.clone(),
+            collector: self.collector.clone(),
+            yielder: None,
+            stack_limit: stack_limit(stack),
+            segments: 1,
+            spare_segment: None,
+        }
+    }
+
+    /// Lets the coroutine that runs with this state yield through
+    /// `yielder`.
+    pub fn with_yielder(self, yielder: &LuaYielder) -> Vm<'_> {
+        Vm {
+            yielder: Some(yielder),
+            ..self
+        }
+    }
+
     fn setup_builtins(&mut self) {
         self.globals.borrow_mut().insert(
             "print".to_string(),
@@ -45,96 +173,347 @@ impl Vm {
             "tostring".to_string(),
             Value::Function(Function::Native(to_string)),
         );
+        self.globals.borrow_mut().insert(
+            "select".to_string(),
+            Value::Function(Function::Native(select)),
+        );
+        self.globals.borrow_mut().insert(
+            "next".to_string(),
+            Value::Function(Function::Native(next)),
+        );
+        self.globals.borrow_mut().insert(
+            "pairs".to_string(),
+            Value::Function(Function::Native(pairs)),
+        );
+        self.globals.borrow_mut().insert(
+            "ipairs".to_string(),
+            Value::Function(Function::Native(ipairs)),
+        );
+
+        self.globals.borrow_mut().insert(
+            "setmetatable".to_string(),
+            Value::Function(Function::Native(set_metatable)),
+        );
+        self.globals.borrow_mut().insert(
+            "getmetatable".to_string(),
+            Value::Function(Function::Native(get_metatable)),
+        );
+        self.globals.borrow_mut().insert(
+            "rawget".to_string(),
+            Value::Function(Function::Native(raw_get)),
+        );
+        self.globals.borrow_mut().insert(
+            "rawset".to_string(),
+            Value::Function(Function::Native(raw_set)),
+        );
+        self.globals.borrow_mut().insert(
+            "rawequal".to_string(),
+            Value::Function(Function::Native(raw_equal)),
+        );
+        self.globals.borrow_mut().insert(
+            "rawlen".to_string(),
+            Value::Function(Function::Native(raw_len)),
+        );
+        self.globals.borrow_mut().insert(
+            "collectgarbage".to_string(),
+            Value::Function(Function::Native(collect_garbage)),
+        );
+        self.globals.borrow_mut().insert(
+            "error".to_string(),
+            Value::Function(Function::Native(error)),
+        );
+        self.globals.borrow_mut().insert(
+            "pcall".to_string(),
+            Value::Function(Function::Native(pcall)),
+        );
+        self.globals.borrow_mut().insert(
+            "xpcall".to_string(),
+            Value::Function(Function::Native(xpcall)),
+        );
+        self.globals.borrow_mut().insert(
+            "assert".to_string(),
+            Value::Function(Function::Native(assert)),
+        );
+
+        let math = Value::new_table();
+        let math_fields = [
+            ("type", Value::Function(Function::Native(math_type))),
+            ("tointeger", Value::Function(Function::Native(math_to_integer))),
+            ("maxinteger", Value::Integer(i64::MAX)),
+            ("mininteger", Value::Integer(i64::MIN)),
+        ];
+        for (name, value) in math_fields {
+            self.set_index(&math, Value::String(name.to_string()), value)
+                .expect("setting a library field");
+        }
+        self.globals.borrow_mut().insert("math".to_string(), math);
+
+        let coroutine = Value::new_table();
+        let coroutine_fields = [
+            ("create", Value::Function(Function::Native(coroutine_create))),
+            ("resume", Value::Function(Function::Native(coroutine_resume))),
+            ("yield", Value::Function(Function::Native(coroutine_yield))),
+            ("status", Value::Function(Function::Native(coroutine_status))),
+            ("wrap", Value::Function(Function::Native(coroutine_wrap))),
+            (
+                "isyieldable",
+                Value::Function(Function::Native(coroutine_is_yieldable)),
+            ),
+            ("running", Value::Function(Function::Native(coroutine_running))),
+            ("close", Value::Function(Function::Native(coroutine_close))),
+        ];
+        for (name, value) in coroutine_fields {
+            self.set_index(&coroutine, Value::String(name.to_string()), value)
+                .expect("setting a library field");
+        }
+        self.globals
+            .borrow_mut()
+            .insert("coroutine".to_string(), coroutine);
     }
 
-    pub fn execute(&mut self, stmts: Vec<Stmt>) -> Value {
-        let mut result = Value::Nil;
-        for stmt in stmts {
-            result = self.execute_stmt(&stmt);
-            if let Value::Function(Function::UserDefined { .. }) = &result {
+    /// Runs a chunk in its own call frame, as a call to the chunk function
+    /// with `args` as its `...`.
+    pub fn execute(&mut self, chunk: Rc<FunctionBody>, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+        self.on_lua_stack(|vm| vm.execute_user_function(&chunk, Rc::new(HashMap::new()), args))
+    }
+
+    /// Runs a line of interactive input. Top-level locals it declares stay
+    /// visible to the lines that follow, even when it fails, except
+    /// to-be-closed ones: those are closed when the line ends.
+    pub fn execute_interactive(&mut self, chunk: Rc<FunctionBody>) -> LuaResult<Vec<Value>> {
+        self.on_lua_stack(|vm| vm.run_interactive(chunk))
+    }
+
+    fn run_interactive(&mut self, chunk: Rc<FunctionBody>) -> LuaResult<Vec<Value>> {
+        self.call_stack.push(CallFrame {
+            locals: std::mem::take(&mut self.session_locals),
+            upvalues: Rc::new(HashMap::new()),
+            varargs: Vec::new(),
+            shadowed: Vec::new(),
+            to_close: Vec::new(),
+            source: chunk.source.clone(),
+            line: 0,
+        });
+        let flow = self.execute_statements(&chunk.body);
+        // Closing empties the list, so note which locals it covers first
+        let closed: HashSet<usize> = self
+            .call_stack
+            .last()
+            .expect("interactive frame")
+            .to_close
+            .iter()
+            .map(|(position, _)| *position)
+            .collect();
+        let flow = match flow {
+            Ok(flow) => self.close_variables(0, None).map(|()| flow),
+            Err(error) => Err(self
+                .close_variables(0, Some(error))
+                .expect_err("closing after an error fails")),
+        };
+        let mut frame = self.call_stack.pop().expect("interactive frame");
+        // Later lines see whatever a closed local hid instead of the dead
+        // local itself. Only the latest declaration of a name is visible,
+        // so the walk stops at the first one that was not closed.
+        let mut settled = HashSet::new();
+        for (position, (name, previous)) in frame.shadowed.into_iter().enumerate().rev() {
+            if settled.contains(&name) {
                 continue;
             }
+            if closed.contains(&position) {
+                match previous {
+                    Some(variable) => frame.locals.insert(name, variable),
+                    None => frame.locals.remove(&name),
+                };
+            } else {
+                settled.insert(name);
+            }
         }
-        result
+        self.session_locals = frame.locals;
+        match flow? {
+            ControlFlow::Return(values) => Ok(values),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Shuts the interpreter down, running the finalizers of every table
+    /// that still has one, as `lua_close` does.
+    pub fn close(&mut self) {
+        let tables = self.collector.borrow_mut().take_all_finalizers();
+        self.on_lua_stack(|vm| {
+            for table in tables {
+                vm.finalize(Value::Table(table));
+            }
+        });
     }
 
-    fn execute_stmt(&mut self, stmt: &Stmt) -> Value {
-        match stmt {
-            Stmt::Expr(expr) => self.evaluate_expr(expr),
-            Stmt::Assignment { variables, values } => self.execute_assignment(variables, values),
-            Stmt::LocalAssignment { variables, values } => {
-                self.execute_local_assignment(variables, values)
+    fn execute_stmt(&mut self, stmt: &Stmt) -> LuaResult<ControlFlow> {
+        if self.collector.borrow().has_pending_finalizers() {
+            self.run_finalizers();
+        }
+        if let Some(frame) = self.call_stack.last_mut() {
+            frame.line = stmt.line;
+        }
+        match &stmt.kind {
+            StmtKind::Expr(expr) => {
+                self.evaluate_expr(expr)?;
             }
-            Stmt::If {
+            StmtKind::Assignment { targets, values } => self.execute_assignment(targets, values)?,
+            StmtKind::LocalAssignment {
+                variables,
+                values,
+                close,
+            } => self.execute_local_assignment(variables, values, *close)?,
+            StmtKind::If {
                 condition,
                 then_block,
                 else_if_blocks,
                 else_block,
-            } => self.execute_if(condition, then_block, else_if_blocks, else_block),
-            Stmt::While { condition, body } => self.execute_while(condition, body),
-            Stmt::Repeat { body, condition } => self.execute_repeat(body, condition),
-            Stmt::For {
+            } => return self.execute_if(condition, then_block, else_if_blocks, else_block),
+            StmtKind::While { condition, body } => return self.execute_while(condition, body),
+            StmtKind::Repeat { body, condition } => return self.execute_repeat(body, condition),
+            StmtKind::For {
                 variable,
                 start,
                 end,
                 step,
                 body,
-            } => self.execute_for(variable, start, end, step, body),
-            Stmt::Function {
-                name,
-                parameters,
+            } => return self.execute_for(variable, start, end, step, body),
+            StmtKind::GenericFor {
+                variables,
+                expressions,
                 body,
-            } => self.execute_function(name, parameters, body),
-            Stmt::LocalFunction {
-                name,
-                parameters,
-                body,
-            } => self.execute_local_function(name, parameters, body),
-            Stmt::Return(values) => self.execute_return(values),
-            Stmt::Break => Value::Nil,
+            } => return self.execute_generic_for(variables, expressions, body),
+            StmtKind::LocalFunction { name, function } => {
+                self.execute_local_function(name, function)
+            }
+            StmtKind::Do(body) => return self.execute_block(body),
+            StmtKind::Return(values) => {
+                return Ok(ControlFlow::Return(self.execute_return(values)?))
+            }
+            StmtKind::Break => return Ok(ControlFlow::Break),
         }
+        Ok(ControlFlow::Normal)
     }
 
-    fn execute_assignment(&mut self, variables: &Vec<String>, values: &Vec<Expr>) -> Value {
-        let evaluated_values: Vec<Value> = values.iter().map(|v| self.evaluate_expr(v)).collect();
+    fn execute_assignment(&mut self, targets: &[LValue], values: &[Expr]) -> LuaResult<()> {
+        // Like the reference implementation: target tables and keys first,
+        // then every value, and only then the stores, from right to left.
+        // This is what makes `a, b = b, a` swap.
+        let places = targets
+            .iter()
+            .map(|t| self.resolve_place(t))
+            .collect::<LuaResult<Vec<Place>>>()?;
+        let mut evaluated_values = self.evaluate_expr_list(values)?;
+        evaluated_values.resize(places.len(), Value::Nil);
 
-        for (i, var) in variables.iter().enumerate() {
-            let value = evaluated_values.get(i).unwrap_or(&Value::Nil).clone();
-            self.globals.borrow_mut().insert(var.clone(), value);
+        for (place, value) in places.into_iter().zip(evaluated_values).rev() {
+            self.assign(place, value)?;
         }
+        Ok(())
+    }
 
-        Value::Nil
+    fn resolve_place(&mut self, target: &LValue) -> LuaResult<Place> {
+        let place = match target {
+            LValue::Name(name) => match self.lookup_local(name) {
+                Some(variable) if self.is_upvalue(name, &variable) => Place::Upvalue(variable),
+                Some(variable) => Place::Local(variable),
+                None => Place::Global(name.clone()),
+            },
+            LValue::Index {
+                table: table_expr,
+                key,
+            } => {
+                let table = self.evaluate_expr(table_expr)?;
+                self.check_indexable(&table, table_expr)?;
+                match key {
+                    Expr::String(name) => Place::Field {
+                        table,
+                        key: Value::String(name.clone()),
+                    },
+                    _ => Place::Index {
+                        table,
+                        key: self.evaluate_expr(key)?,
+                    },
+                }
+            }
+        };
+        Ok(place)
     }
 
-    fn execute_local_assignment(&mut self, variables: &Vec<String>, values: &Vec<Expr>) -> Value {
-        let evaluated_values: Vec<Value> = values.iter().map(|v| self.evaluate_expr(v)).collect();
+    fn assign(&mut self, place: Place, value: Value) -> LuaResult<()> {
+        match place {
+            Place::Local(variable) | Place::Upvalue(variable) => *variable.borrow_mut() = value,
+            Place::Global(name) => {
+                self.globals.borrow_mut().insert(name, value);
+            }
+            Place::Field { table, key } | Place::Index { table, key } => {
+                self.set_index(&table, key, value)?
+            }
+        }
+        Ok(())
+    }
 
-        let current_frame = self.call_stack.last_mut().unwrap_or_else(|| {
-            panic!("No call frame available");
-        });
+    /// `table[key] = value`. Assigning a field the table lacks goes through
+    /// `__newindex`, which is either called with the table, key and value or
+    /// assigned in turn.
+    fn set_index(&mut self, table: &Value, key: Value, value: Value) -> LuaResult<()> {
+        let mut current = table.clone();
+        for _ in 0..MAX_META_CHAIN {
+            let handler = match &current {
+                Value::Table(t) if t.borrow().get(&key) != Value::Nil => Value::Nil,
+                _ => self.get_metamethod(&current, "__newindex"),
+            };
+            match handler {
+                Value::Nil => {
+                    let Value::Table(t) = &current else {
+                        return Err(self.type_error(&current, "index", None));
+                    };
+                    let result = t.borrow_mut().set(key, value);
+                    return result.map_err(|message| self.runtime_error(message));
+                }
+                Value::Function(_) => {
+                    self.call_function(handler, vec![current, key, value])?;
+                    return Ok(());
+                }
+                _ => current = handler,
+            }
+        }
+        Err(self.runtime_error("'__newindex' chain too long; possible loop"))
+    }
+
+    fn execute_local_assignment(
+        &mut self,
+        variables: &[String],
+        values: &[Expr],
+        close: Option<usize>,
+    ) -> LuaResult<()> {
+        let evaluated_values = self.evaluate_expr_list(values)?;
 
         for (i, var) in variables.iter().enumerate() {
             let value = evaluated_values.get(i).unwrap_or(&Value::Nil).clone();
-            current_frame.locals.borrow_mut().insert(var.clone(), value);
+            if close == Some(i) {
+                self.declare_to_be_closed(var, value)?;
+            } else {
+                self.declare_local(var, value);
+            }
         }
-
-        Value::Nil
+        Ok(())
     }
 
     fn execute_if(
         &mut self,
         condition: &Expr,
-        then_block: &Vec<Stmt>,
-        else_if_blocks: &Vec<(Expr, Vec<Stmt>)>,
+        then_block: &[Stmt],
+        else_if_blocks: &[(Expr, Vec<Stmt>)],
         else_block: &Option<Vec<Stmt>>,
-    ) -> Value {
-        let cond_value = self.evaluate_expr(condition);
+    ) -> LuaResult<ControlFlow> {
+        let cond_value = self.evaluate_expr(condition)?;
         if cond_value.is_truthy() {
             return self.execute_block(then_block);
         }
 
         for (else_if_cond, else_if_body) in else_if_blocks {
-            let else_if_value = self.evaluate_expr(else_if_cond);
+            let else_if_value = self.evaluate_expr(else_if_cond)?;
             if else_if_value.is_truthy() {
                 return self.execute_block(else_if_body);
             }
@@ -144,144 +523,404 @@ impl Vm {
             return self.execute_block(else_body);
         }
 
-        Value::Nil
+        Ok(ControlFlow::Normal)
     }
 
-    fn execute_while(&mut self, condition: &Expr, body: &Vec<Stmt>) -> Value {
+    fn execute_while(&mut self, condition: &Expr, body: &[Stmt]) -> LuaResult<ControlFlow> {
         loop {
-            let cond_value = self.evaluate_expr(condition);
+            let cond_value = self.evaluate_expr(condition)?;
             if !cond_value.is_truthy() {
                 break;
             }
-            self.execute_block(body);
+            match self.execute_block(body)? {
+                ControlFlow::Normal => {}
+                ControlFlow::Break => break,
+                flow @ ControlFlow::Return(_) => return Ok(flow),
+            }
         }
-        Value::Nil
+        Ok(ControlFlow::Normal)
     }
 
-    fn execute_repeat(&mut self, body: &Vec<Stmt>, condition: &Expr) -> Value {
+    fn execute_repeat(&mut self, body: &[Stmt], condition: &Expr) -> LuaResult<ControlFlow> {
         loop {
-            self.execute_block(body);
-            let cond_value = self.evaluate_expr(condition);
-            if cond_value.is_truthy() {
-                break;
+            // The condition is still inside the scope of the body's locals
+            let scope = self.enter_scope();
+            let result = self.with_closing(scope, |vm| {
+                let flow = vm.execute_statements(body)?;
+                let finished = match flow {
+                    ControlFlow::Normal => vm.evaluate_expr(condition)?.is_truthy(),
+                    _ => true,
+                };
+                Ok((flow, finished))
+            });
+            let (flow, finished) = self.exit_scope(scope).and(result)?;
+            match flow {
+                ControlFlow::Normal if !finished => {}
+                ControlFlow::Return(_) => return Ok(flow),
+                _ => break,
             }
         }
-        Value::Nil
+        Ok(ControlFlow::Normal)
     }
 
+    /// Runs a numeric for. The loop counts in integers when the initial
+    /// value and the step are integers, and in floats otherwise.
     fn execute_for(
         &mut self,
-        variable: &String,
+        variable: &str,
         start: &Expr,
         end: &Expr,
         step: &Option<Expr>,
-        body: &Vec<Stmt>,
-    ) -> Value {
-        let start_val = self.evaluate_expr(start).to_number().unwrap_or(0.0);
-        let end_val = self.evaluate_expr(end).to_number().unwrap_or(0.0);
-        let step_val = step
-            .as_ref()
-            .map(|s| self.evaluate_expr(s).to_number().unwrap_or(1.0))
-            .unwrap_or(1.0);
-
-        let mut current = start_val;
-        while (step_val > 0.0 && current <= end_val) || (step_val < 0.0 && current >= end_val) {
-            let current_frame = self.call_stack.last_mut().unwrap_or_else(|| {
-                panic!("No call frame available");
-            });
-            current_frame
-                .locals
-                .borrow_mut()
-                .insert(variable.clone(), Value::Number(current));
+        body: &[Stmt],
+    ) -> LuaResult<ControlFlow> {
+        let start_val = self.evaluate_expr(start)?;
+        let end_val = self.evaluate_expr(end)?;
+        let step_val = match step {
+            Some(step) => self.evaluate_expr(step)?,
+            None => Value::Integer(1),
+        };
+        for (value, what) in [
+            (&start_val, "initial value"),
+            (&end_val, "limit"),
+            (&step_val, "step"),
+        ] {
+            if !matches!(value, Value::Integer(_) | Value::Number(_)) {
+                return Err(self.runtime_error(&format!("'for' {} must be a number", what)));
+            }
+        }
 
-            self.execute_block(body);
-            current += step_val;
+        if let (Value::Integer(start), Value::Integer(step)) = (&start_val, &step_val) {
+            let (start, step) = (*start, *step);
+            if step == 0 {
+                return Err(self.runtime_error("'for' step is zero"));
+            }
+            let Some(mut remaining) = for_iteration_count(start, &end_val, step) else {
+                return Ok(ControlFlow::Normal);
+            };
+            let mut current = start;
+            loop {
+                match self.execute_for_body(variable, Value::Integer(current), body)? {
+                    ControlFlow::Normal => {}
+                    ControlFlow::Break => break,
+                    flow @ ControlFlow::Return(_) => return Ok(flow),
+                }
+                if remaining == 0 {
+                    break;
+                }
+                remaining -= 1;
+                current = current.wrapping_add(step);
+            }
+        } else {
+            let start = start_val.to_number().unwrap_or(0.0);
+            let end = end_val.to_number().unwrap_or(0.0);
+            let step = step_val.to_number().unwrap_or(0.0);
+            if step == 0.0 {
+                return Err(self.runtime_error("'for' step is zero"));
+            }
+            let mut current = start;
+            while (step > 0.0 && current <= end) || (step < 0.0 && current >= end) {
+                match self.execute_for_body(variable, Value::Number(current), body)? {
+                    ControlFlow::Normal => {}
+                    ControlFlow::Break => break,
+                    flow @ ControlFlow::Return(_) => return Ok(flow),
+                }
+                current += step;
+            }
         }
-        Value::Nil
+        Ok(ControlFlow::Normal)
     }
 
-    fn execute_function(
+    fn execute_for_body(
         &mut self,
-        name: &String,
-        parameters: &Vec<String>,
-        body: &Vec<Stmt>,
-    ) -> Value {
-        let function = Value::Function(Function::UserDefined {
-            parameters: parameters.clone(),
-            body: body.clone(),
-            closure: Rc::new(RefCell::new(HashMap::new())),
-        });
-        self.globals.borrow_mut().insert(name.clone(), function);
-        Value::Nil
+        variable: &str,
+        value: Value,
+        body: &[Stmt],
+    ) -> LuaResult<ControlFlow> {
+        // Each iteration gets a fresh binding, so closures created in the
+        // body keep the value of the iteration they were created in.
+        let scope = self.enter_scope();
+        self.declare_local(variable, value);
+        let flow = self.execute_block(body);
+        self.exit_scope(scope).and(flow)
     }
 
-    fn execute_local_function(
+    /// Runs `for vars in explist do ... end`: the list yields an iterator
+    /// function, an invariant state and the initial control value, and the
+    /// iterator is called with the state and the last control value until
+    /// its first result is nil.
+    fn execute_generic_for(
         &mut self,
-        name: &String,
-        parameters: &Vec<String>,
-        body: &Vec<Stmt>,
-    ) -> Value {
-        let function = Value::Function(Function::UserDefined {
-            parameters: parameters.clone(),
-            body: body.clone(),
-            closure: Rc::new(RefCell::new(HashMap::new())),
+        variables: &[String],
+        expressions: &[Expr],
+        body: &[Stmt],
+    ) -> LuaResult<ControlFlow> {
+        let mut values = self.evaluate_expr_list(expressions)?.into_iter();
+        let iterator = values.next().unwrap_or(Value::Nil);
+        let state = values.next().unwrap_or(Value::Nil);
+        let control = values.next().unwrap_or(Value::Nil);
+        // The optional fourth value is a to-be-closed variable of the loop,
+        // hidden from its body, so it is closed however the loop ends
+        let scope = self.enter_scope();
+        let closing = values.next().unwrap_or(Value::Nil);
+        self.declare_to_be_closed("(for state)", closing)?;
+        let flow = self.with_closing(scope, |vm| {
+            vm.iterate(variables, &iterator, &state, control, body)
         });
+        self.exit_scope(scope).and(flow)
+    }
 
-        let current_frame = self.call_stack.last_mut().unwrap_or_else(|| {
-            panic!("No call frame available");
-        });
-        current_frame
-            .locals
-            .borrow_mut()
-            .insert(name.clone(), function);
-        Value::Nil
+    /// The loop of a generic for: calls the iterator and runs the body
+    /// until the first result of the iterator is nil.
+    fn iterate(
+        &mut self,
+        variables: &[String],
+        iterator: &Value,
+        state: &Value,
+        mut control: Value,
+        body: &[Stmt],
+    ) -> LuaResult<ControlFlow> {
+        if !self.is_callable(iterator) {
+            let info = "for iterator 'for iterator'".to_string();
+            return Err(self.type_error(iterator, "call", Some(info)));
+        }
+        loop {
+            let mut results = self
+                .call_function(iterator.clone(), vec![state.clone(), control.clone()])?
+                .into_iter();
+            let first = results.next().unwrap_or(Value::Nil);
+            if first == Value::Nil {
+                return Ok(ControlFlow::Normal);
+            }
+            control = first.clone();
+
+            let scope = self.enter_scope();
+            self.declare_local(&variables[0], first);
+            for variable in &variables[1..] {
+                self.declare_local(variable, results.next().unwrap_or(Value::Nil));
+            }
+            let flow = self.execute_block(body);
+
+            match self.exit_scope(scope).and(flow)? {
+                ControlFlow::Normal => {}
+                ControlFlow::Break => return Ok(ControlFlow::Normal),
+                flow @ ControlFlow::Return(_) => return Ok(flow),
+            }
+        }
     }
 
-    fn execute_return(&mut self, values: &Option<Vec<Expr>>) -> Value {
-        match values {
-            Some(exprs) => {
-                if exprs.len() == 1 {
-                    self.evaluate_expr(&exprs[0])
-                } else {
-                    Value::Nil
+    /// Declares a local whose value is closed when its block ends.
+    fn declare_to_be_closed(&mut self, name: &str, value: Value) -> LuaResult<()> {
+        self.check_closable(&value, name)?;
+        self.declare_local(name, value.clone());
+        let frame = self.call_stack.last_mut().expect("no call frame");
+        frame.to_close.push((frame.shadowed.len() - 1, value));
+        Ok(())
+    }
+
+    /// A to-be-closed variable must hold nil, false or a value with a
+    /// `__close` metamethod.
+    fn check_closable(&self, value: &Value, name: &str) -> LuaResult<()> {
+        if value.is_truthy() && self.get_metamethod(value, "__close") == Value::Nil {
+            return Err(
+                self.runtime_error(&format!("variable '{}' got a non-closable value", name))
+            );
+        }
+        Ok(())
+    }
+
+    /// Calls the `__close` metamethod of a to-be-closed value going out of
+    /// scope, passing the error that made it go out of scope, if any.
+    fn close_value(&mut self, value: Value, error: Value) -> LuaResult<()> {
+        if value.is_truthy() {
+            let handler = self.get_metamethod(&value, "__close");
+            self.call_function(handler, vec![value, error])?;
+        }
+        Ok(())
+    }
+
+    /// Closes the to-be-closed variables of the current frame declared
+    /// since `scope`, the most recent first, passing each the error being
+    /// propagated, if any. An error in a `__close` metamethod replaces it
+    /// for the variables after, and the closing fails with whichever error
+    /// is left.
+    fn close_variables(&mut self, scope: usize, mut error: Option<LuaError>) -> LuaResult<()> {
+        while let Some(frame) = self.call_stack.last_mut() {
+            match frame.to_close.last() {
+                Some((position, _)) if *position >= scope => {
+                    let (_, value) = frame.to_close.pop().unwrap();
+                    let passed = error.as_ref().map_or(Value::Nil, |e| e.value.clone());
+                    if let Err(e) = self.close_value(value, passed) {
+                        error = Some(e);
+                    }
                 }
+                _ => break,
             }
-            None => Value::Nil,
         }
+        match error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the part of a block that `run` does. When an error escapes,
+    /// the to-be-closed variables of the block are closed with the error
+    /// before it propagates.
+    fn with_closing<T>(
+        &mut self,
+        scope: usize,
+        run: impl FnOnce(&mut Self) -> LuaResult<T>,
+    ) -> LuaResult<T> {
+        run(self).map_err(|error| {
+            self.close_variables(scope, Some(error))
+                .expect_err("closing after an error fails")
+        })
+    }
+
+    /// Calls the `__gc` metamethods of the tables the collector found
+    /// unreachable.
+    fn run_finalizers(&mut self) {
+        let tables = self.collector.borrow_mut().take_pending_finalizers();
+        for table in tables {
+            self.finalize(Value::Table(table));
+        }
+    }
+
+    /// Calls the finalizer of a value. Errors in finalizers are ignored,
+    /// as the reference implementation does when warnings are off.
+    fn finalize(&mut self, value: Value) {
+        let handler = self.get_metamethod(&value, "__gc");
+        if handler != Value::Nil {
+            let _ = self.call_function(handler, vec![value]);
+        }
+    }
+
+    fn execute_local_function(&mut self, name: &str, function: &Rc<FunctionBody>) {
+        // Declared before the closure is created so the function can see
+        // itself and recurse.
+        let variable = self.declare_local(name, Value::Nil);
+        *variable.borrow_mut() = self.create_closure(function);
+    }
+
+    /// Instantiates a function literal, capturing the variables it refers to
+    /// from the scope it is defined in.
+    fn create_closure(&mut self, function: &Rc<FunctionBody>) -> Value {
+        let mut closure = HashMap::new();
+        if let Some(frame) = self.call_stack.last() {
+            for name in &function.upvalues {
+                if let Some(variable) = frame.locals.get(name) {
+                    closure.insert(name.clone(), variable.clone());
+                }
+            }
+        }
+        let closure = Rc::new(closure);
+        let mut collector = self.collector.borrow_mut();
+        // Only captured variables can be part of a cycle
+        for variable in closure.values() {
+            collector.track_cell(variable);
+        }
+        collector.track_closure(&closure);
+        drop(collector);
+        Value::Function(Function::UserDefined {
+            function: function.clone(),
+            closure,
+        })
+    }
+
+    /// Introduces a new local in the current frame, hiding any variable of
+    /// the same name without touching closures that captured it.
+    fn declare_local(&mut self, name: &str, value: Value) -> Variable {
+        // Every chunk runs inside a frame, so there is always one here
+        let variable = Rc::new(RefCell::new(value));
+        let current_frame = self.call_stack.last_mut().expect("no call frame");
+        let previous = current_frame
+            .locals
+            .insert(name.to_string(), variable.clone());
+        current_frame.shadowed.push((name.to_string(), previous));
+        variable
+    }
+
+    /// Marks the start of a block; locals declared after this point are
+    /// discarded by the matching `exit_scope`.
+    fn enter_scope(&self) -> usize {
+        self.call_stack
+            .last()
+            .map_or(0, |frame| frame.shadowed.len())
+    }
+
+    /// Ends a block, closing its to-be-closed variables and restoring the
+    /// bindings its locals hid. The bindings are restored even when
+    /// closing fails.
+    fn exit_scope(&mut self, scope: usize) -> LuaResult<()> {
+        let closed = self.close_variables(scope, None);
+        if let Some(frame) = self.call_stack.last_mut() {
+            while frame.shadowed.len() > scope {
+                let (name, previous) = frame.shadowed.pop().unwrap();
+                match previous {
+                    Some(variable) => frame.locals.insert(name, variable),
+                    None => frame.locals.remove(&name),
+                };
+            }
+        }
+        closed
+    }
+
+    fn execute_return(&mut self, values: &Option<Vec<Expr>>) -> LuaResult<Vec<Value>> {
+        match values {
+            Some(exprs) => self.evaluate_expr_list(exprs),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn execute_block(&mut self, stmts: &[Stmt]) -> LuaResult<ControlFlow> {
+        let scope = self.enter_scope();
+        let flow = self.with_closing(scope, |vm| vm.execute_statements(stmts));
+        self.exit_scope(scope).and(flow)
     }
 
-    fn execute_block(&mut self, stmts: &Vec<Stmt>) -> Value {
-        let mut result = Value::Nil;
+    /// Runs statements in order until one of them breaks out of a loop or
+    /// returns from the function.
+    fn execute_statements(&mut self, stmts: &[Stmt]) -> LuaResult<ControlFlow> {
         for stmt in stmts {
-            result = self.execute_stmt(stmt);
+            match self.execute_stmt(stmt)? {
+                ControlFlow::Normal => {}
+                flow => return Ok(flow),
+            }
         }
-        result
+        Ok(ControlFlow::Normal)
     }
 
-    fn evaluate_expr(&mut self, expr: &Expr) -> Value {
-        match expr {
+    fn evaluate_expr(&mut self, expr: &Expr) -> LuaResult<Value> {
+        // Nested expressions recurse as deeply as calls do
+        if stack_pointer() < self.stack_limit {
+            return self.on_new_segment(|vm| vm.evaluate_expr(expr));
+        }
+        let value = match expr {
+            Expr::Integer(n) => Value::Integer(*n),
             Expr::Number(n) => Value::Number(*n),
             Expr::String(s) => Value::String(s.clone()),
             Expr::Boolean(b) => Value::Boolean(*b),
             Expr::Nil => Value::Nil,
             Expr::Identifier(name) => self.get_variable(name),
-            Expr::UnaryOp { operator, operand } => self.evaluate_unary_op(operator, operand),
+            Expr::UnaryOp { operator, operand } => self.evaluate_unary_op(operator, operand)?,
             Expr::BinaryOp {
                 left,
                 operator,
                 right,
-            } => self.evaluate_binary_op(left, operator, right),
-            Expr::FunctionCall { name, arguments } => self.evaluate_function_call(name, arguments),
-            Expr::TableAccess { table, key } => self.evaluate_table_access(table, key),
-            Expr::TableConstructor { fields } => self.evaluate_table_constructor(fields),
-        }
+            } => self.evaluate_binary_op(left, operator, right)?,
+            Expr::FunctionCall { .. } | Expr::MethodCall { .. } | Expr::Vararg => {
+                first(self.evaluate_multi(expr)?)
+            }
+            Expr::Paren(inner) => self.evaluate_expr(inner)?,
+            Expr::TableAccess { table, key } => self.evaluate_table_access(table, key)?,
+            Expr::TableConstructor { fields } => self.evaluate_table_constructor(fields)?,
+            Expr::Function(function) => self.create_closure(function),
+        };
+        Ok(value)
     }
 
     fn get_variable(&mut self, name: &str) -> Value {
-        for frame in self.call_stack.iter().rev() {
-            if let Some(value) = frame.locals.borrow().get(name) {
-                return value.clone();
-            }
+        if let Some(variable) = self.lookup_local(name) {
+            return variable.borrow().clone();
         }
 
         if let Some(value) = self.globals.borrow().get(name) {
@@ -291,12 +930,52 @@ impl Vm {
         Value::Nil
     }
 
-    fn evaluate_unary_op(&mut self, operator: &UnaryOperator, operand: &Expr) -> Value {
-        let value = self.evaluate_expr(operand);
-        match operator {
-            UnaryOperator::Not => value.not(),
-            UnaryOperator::Minus => value.negate(),
-            UnaryOperator::Length => value.length(),
+    /// Resolves a name against the locals and upvalues visible in the
+    /// running function. Callers' locals are never visible.
+    fn lookup_local(&self, name: &str) -> Option<Variable> {
+        self.call_stack.last()?.locals.get(name).cloned()
+    }
+
+    /// Whether a resolved local actually belongs to an enclosing function.
+    fn is_upvalue(&self, name: &str, variable: &Variable) -> bool {
+        self.call_stack
+            .last()
+            .and_then(|frame| frame.upvalues.get(name))
+            .is_some_and(|upvalue| Rc::ptr_eq(upvalue, variable))
+    }
+
+    fn evaluate_unary_op(&mut self, operator: &UnaryOperator, operand: &Expr) -> LuaResult<Value> {
+        let value = self.evaluate_expr(operand)?;
+        let (result, event) = match operator {
+            UnaryOperator::Not => return Ok(value.not()),
+            UnaryOperator::Length => return self.length(value, operand),
+            UnaryOperator::Minus => (value.negate(), "__unm"),
+            UnaryOperator::BitwiseNot => (value.bitwise_not(), "__bnot"),
+        };
+        if result != Value::Nil {
+            return Ok(result);
+        }
+        match self.call_binary_metamethod(value.clone(), value.clone(), event)? {
+            Some(result) => Ok(result),
+            None => Err(self.operand_error(event, [(&value, operand), (&value, operand)])),
+        }
+    }
+
+    /// The length operator: strings have their byte length, and a `__len`
+    /// metamethod takes precedence over the border of a table.
+    fn length(&mut self, value: Value, operand: &Expr) -> LuaResult<Value> {
+        if let Value::String(_) = value {
+            return Ok(value.length());
+        }
+        let handler = self.get_metamethod(&value, "__len");
+        if handler != Value::Nil {
+            return Ok(first(self.call_function(handler, vec![value])?));
+        }
+        match value.length() {
+            Value::Nil => {
+                Err(self.type_error(&value, "get length of", self.variable_info(operand)))
+            }
+            length => Ok(length),
         }
     }
 
@@ -305,144 +984,1729 @@ impl Vm {
         left: &Expr,
         operator: &BinaryOperator,
         right: &Expr,
-    ) -> Value {
-        let left_val = self.evaluate_expr(left);
-        let right_val = self.evaluate_expr(right);
+    ) -> LuaResult<Value> {
+        let left_val = self.evaluate_expr(left)?;
 
+        // `and` and `or` only evaluate the right operand when the left one
+        // does not decide the result, and yield the deciding operand itself
         match operator {
-            BinaryOperator::Add => left_val.add(&right_val),
-            BinaryOperator::Subtract => left_val.subtract(&right_val),
-            BinaryOperator::Multiply => left_val.multiply(&right_val),
-            BinaryOperator::Divide => left_val.divide(&right_val),
-            BinaryOperator::Modulo => left_val.modulo(&right_val),
-            BinaryOperator::Power => left_val.power(&right_val),
-            BinaryOperator::Concat => left_val.concat(&right_val),
-            BinaryOperator::Equal => left_val.equal(&right_val),
-            BinaryOperator::NotEqual => left_val.not_equal(&right_val),
-            BinaryOperator::LessThan => left_val.less_than(&right_val),
-            BinaryOperator::LessEqual => left_val.less_equal(&right_val),
-            BinaryOperator::GreaterThan => left_val.greater_than(&right_val),
-            BinaryOperator::GreaterEqual => left_val.greater_equal(&right_val),
-            BinaryOperator::And => Value::Boolean(left_val.is_truthy() && right_val.is_truthy()),
-            BinaryOperator::Or => Value::Boolean(left_val.is_truthy() || right_val.is_truthy()),
-        }
-    }
-
-    fn evaluate_function_call(&mut self, name: &String, arguments: &Vec<Expr>) -> Value {
-        let func = self.get_variable(name);
-        let evaluated_args: Vec<Value> = arguments
-            .iter()
-            .map(|arg| self.evaluate_expr(arg))
-            .collect();
-
-        match func {
-            Value::Function(Function::Native(native_func)) => native_func(self, evaluated_args),
-            Value::Function(Function::UserDefined {
-                parameters,
-                body,
-                closure,
-            }) => self.execute_user_function(&parameters, &body, &closure, evaluated_args),
-            _ => Value::Nil,
+            BinaryOperator::And if !left_val.is_truthy() => return Ok(left_val),
+            BinaryOperator::Or if left_val.is_truthy() => return Ok(left_val),
+            BinaryOperator::And | BinaryOperator::Or => return self.evaluate_expr(right),
+            _ => {}
         }
-    }
 
-    fn execute_user_function(
-        &mut self,
-        parameters: &Vec<String>,
-        body: &Vec<Stmt>,
-        _closure: &Rc<RefCell<HashMap<String, Value>>>,
-        args: Vec<Value>,
-    ) -> Value {
-        let locals = Rc::new(RefCell::new(HashMap::new()));
+        let right_val = self.evaluate_expr(right)?;
+
+        let (operation, event): (fn(&Value, &Value) -> Value, &str) = match operator {
+            BinaryOperator::Add => (Value::add, "__add"),
+            BinaryOperator::Subtract => (Value::subtract, "__sub"),
+            BinaryOperator::Multiply => (Value::multiply, "__mul"),
+            BinaryOperator::Divide => (Value::divide, "__div"),
+            BinaryOperator::FloorDivide => (Value::floor_divide, "__idiv"),
+            BinaryOperator::Modulo => (Value::modulo, "__mod"),
+            BinaryOperator::Power => (Value::power, "__pow"),
+            BinaryOperator::Concat => (Value::concat, "__concat"),
+            BinaryOperator::BitwiseAnd => (Value::bitwise_and, "__band"),
+            BinaryOperator::BitwiseOr => (Value::bitwise_or, "__bor"),
+            BinaryOperator::BitwiseXor => (Value::bitwise_xor, "__bxor"),
+            BinaryOperator::ShiftLeft => (Value::shift_left, "__shl"),
+            BinaryOperator::ShiftRight => (Value::shift_right, "__shr"),
+            BinaryOperator::Equal => {
+                return Ok(Value::Boolean(self.values_equal(&left_val, &right_val)?))
+            }
+            BinaryOperator::NotEqual => {
+                return Ok(Value::Boolean(!self.values_equal(&left_val, &right_val)?))
+            }
+            BinaryOperator::LessThan
+            | BinaryOperator::LessEqual
+            | BinaryOperator::GreaterThan
+            | BinaryOperator::GreaterEqual => {
+                return self.evaluate_comparison(operator, left_val, right_val)
+            }
+            BinaryOperator::And | BinaryOperator::Or => unreachable!(),
+        };
 
-        for (i, param) in parameters.iter().enumerate() {
-            let value = args.get(i).unwrap_or(&Value::Nil).clone();
-            locals.borrow_mut().insert(param.clone(), value);
+        // The operation fails with nil on operands it does not apply to,
+        // which is when a metamethod gets its turn
+        let result = operation(&left_val, &right_val);
+        if result != Value::Nil {
+            return Ok(result);
         }
+        match self.call_binary_metamethod(left_val.clone(), right_val.clone(), event)? {
+            Some(result) => Ok(result),
+            None => Err(self.operand_error(event, [(&left_val, left), (&right_val, right)])),
+        }
+    }
 
-        let frame = CallFrame {
-            locals: locals.clone(),
-            return_value: None,
+    /// The error for operands of an arithmetic, bitwise or concatenation
+    /// operator that neither the operator nor a metamethod handles. It
+    /// blames the first operand at fault, naming the variable it came
+    /// from.
+    fn operand_error(&self, event: &str, operands: [(&Value, &Expr); 2]) -> LuaError {
+        let [left, right] = operands;
+        let is_number = |value: &Value| value.coerce_number().is_some();
+        let (action, (culprit, expr)) = match event {
+            "__idiv" | "__mod" if left.0.is_integer_division_by_zero(right.0) => {
+                let operator = if event == "__idiv" { "//" } else { "%" };
+                return self.runtime_error(&format!("attempt to perform 'n{}0'", operator));
+            }
+            "__concat" => match left.0 {
+                Value::String(_) | Value::Integer(_) | Value::Number(_) => ("concatenate", right),
+                _ => ("concatenate", left),
+            },
+            "__band" | "__bor" | "__bxor" | "__shl" | "__shr" | "__bnot" => {
+                if is_number(left.0) && is_number(right.0) {
+                    let (_, expr) = if left.0.to_integer().is_some() {
+                        right
+                    } else {
+                        left
+                    };
+                    return self.runtime_error(&format!(
+                        "number{} has no integer representation",
+                        describe(self.variable_info(expr))
+                    ));
+                }
+                let culprit = if is_number(left.0) { right } else { left };
+                ("perform bitwise operation on", culprit)
+            }
+            _ => {
+                let culprit = if is_number(left.0) { right } else { left };
+                ("perform arithmetic on", culprit)
+            }
         };
+        self.type_error(culprit, action, self.variable_info(expr))
+    }
 
-        self.call_stack.push(frame);
-
-        let result = self.execute_block(body);
+    /// `==`: primitive equality, then the `__eq` metamethod of either
+    /// operand when two distinct tables are compared.
+    fn values_equal(&mut self, left: &Value, right: &Value) -> LuaResult<bool> {
+        if left.equal(right).is_truthy() {
+            return Ok(true);
+        }
+        if !matches!((left, right), (Value::Table(_), Value::Table(_))) {
+            return Ok(false);
+        }
+        let result = self.call_binary_metamethod(left.clone(), right.clone(), "__eq")?;
+        Ok(result.is_some_and(|result| result.is_truthy()))
+    }
 
-        self.call_stack.pop();
+    fn evaluate_comparison(
+        &mut self,
+        operator: &BinaryOperator,
+        left: Value,
+        right: Value,
+    ) -> LuaResult<Value> {
+        let primitive = matches!(
+            (&left, &right),
+            (
+                Value::Integer(_) | Value::Number(_),
+                Value::Integer(_) | Value::Number(_)
+            ) | (Value::String(_), Value::String(_))
+        );
+        if primitive {
+            return Ok(match operator {
+                BinaryOperator::LessThan => left.less_than(&right),
+                BinaryOperator::LessEqual => left.less_equal(&right),
+                BinaryOperator::GreaterThan => left.greater_than(&right),
+                _ => left.greater_equal(&right),
+            });
+        }
 
-        result
+        // Metamethods only exist for `<` and `<=`; the other two swap the
+        // operands
+        let (event, left, right) = match operator {
+            BinaryOperator::LessThan => ("__lt", left, right),
+            BinaryOperator::LessEqual => ("__le", left, right),
+            BinaryOperator::GreaterThan => ("__lt", right, left),
+            _ => ("__le", right, left),
+        };
+        match self.call_binary_metamethod(left.clone(), right.clone(), event)? {
+            Some(result) => Ok(Value::Boolean(result.is_truthy())),
+            None => Err(self.comparison_error(&left, &right)),
+        }
     }
 
-    fn evaluate_table_access(&mut self, table: &Expr, key: &Expr) -> Value {
-        let table_val = self.evaluate_expr(table);
-        let key_val = self.evaluate_expr(key);
-
-        if let Value::Table(t) = table_val {
-            t.borrow().get(&key_val).cloned().unwrap_or(Value::Nil)
+    fn comparison_error(&self, left: &Value, right: &Value) -> LuaError {
+        let (left, right) = (left.type_name(), right.type_name());
+        if left == right {
+            self.runtime_error(&format!("attempt to compare two {} values", left))
         } else {
-            Value::Nil
+            self.runtime_error(&format!("attempt to compare {} with {}", left, right))
         }
     }
 
-    fn evaluate_table_constructor(&mut self, fields: &Vec<crate::parser::TableField>) -> Value {
-        let table = Value::new_table();
-
-        if let Value::Table(t) = &table {
-            for field in fields {
-                match field {
-                    crate::parser::TableField::Value(expr) => {
-                        let value = self.evaluate_expr(expr);
-                        let key = Value::Number(t.borrow().len() as f64 + 1.0);
-                        t.borrow_mut().insert(key, value);
-                    }
-                    crate::parser::TableField::KeyValue(key, expr) => {
-                        let value = self.evaluate_expr(expr);
-                        t.borrow_mut().insert(Value::String(key.clone()), value);
-                    }
+    /// Converts a value to a string like `tostring`: through `__tostring`
+    /// when there is one, and naming tables after their `__name` field.
+    fn tostring(&mut self, value: &Value) -> LuaResult<String> {
+        let handler = self.get_metamethod(value, "__tostring");
+        if handler != Value::Nil {
+            let result = self.call_function(handler, vec![value.clone()])?;
+            return match result.into_iter().next() {
+                Some(s @ (Value::String(_) | Value::Integer(_) | Value::Number(_))) => {
+                    Ok(s.to_string())
                 }
-            }
+                _ => Err(self.runtime_error("'__tostring' must return a string")),
+            };
         }
-
-        table
+        if let (Value::Table(t), Value::String(name)) =
+            (value, self.get_metamethod(value, "__name"))
+        {
+            return Ok(format!("{}: {:p}", name, Rc::as_ptr(t)));
+        }
+        Ok(value.to_string())
     }
-}
 
-fn print(_vm: &mut Vm, args: Vec<Value>) -> Value {
-    let output: Vec<String> = args.iter().map(|v| v.to_string()).collect();
-    println!("{}", output.join("\t"));
-    Value::Nil
-}
-
-fn type_of(_vm: &mut Vm, args: Vec<Value>) -> Value {
-    if args.len() != 1 {
-        return Value::Nil;
+    /// The metamethod for `event` in the metatable of `value`, or nil.
+    fn get_metamethod(&self, value: &Value, event: &str) -> Value {
+        let metatable = match value {
+            Value::Table(t) => t.borrow().metatable(),
+            _ => None,
+        };
+        match metatable {
+            Some(metatable) => metatable.borrow().get(&Value::String(event.to_string())),
+            None => Value::Nil,
+        }
     }
 
-    let type_name = match &args[0] {
-        Value::Nil => "nil",
-        Value::Boolean(_) => "boolean",
-        Value::Number(_) => "number",
-        Value::String(_) => "string",
-        Value::Table(_) => "table",
-        Value::Function(_) => "function",
-    };
+    /// Calls the metamethod for `event` of the left operand, or else of the
+    /// right one, yielding its first result. None when neither has one.
+    fn call_binary_metamethod(
+        &mut self,
+        left: Value,
+        right: Value,
+        event: &str,
+    ) -> LuaResult<Option<Value>> {
+        let mut handler = self.get_metamethod(&left, event);
+        if handler == Value::Nil {
+            handler = self.get_metamethod(&right, event);
+        }
+        if handler == Value::Nil {
+            return Ok(None);
+        }
+        Ok(Some(first(self.call_function(handler, vec![left, right])?)))
+    }
+
+    fn evaluate_function_call(
+        &mut self,
+        callee: &Expr,
+        arguments: &[Expr],
+    ) -> LuaResult<Vec<Value>> {
+        let func = self.evaluate_expr(callee)?;
+        let evaluated_args = self.evaluate_expr_list(arguments)?;
+
+        if !self.is_callable(&func) {
+            return Err(self.type_error(&func, "call", self.variable_info(callee)));
+        }
+        self.call_function(func, evaluated_args)
+    }
+
+    fn evaluate_method_call(
+        &mut self,
+        object: &Expr,
+        method: &str,
+        arguments: &[Expr],
+    ) -> LuaResult<Vec<Value>> {
+        let object_val = self.evaluate_expr(object)?;
+        self.check_indexable(&object_val, object)?;
+        let func = self.index_value(&object_val, &Value::String(method.to_string()))?;
+
+        let mut evaluated_args = vec![object_val];
+        evaluated_args.extend(self.evaluate_expr_list(arguments)?);
+
+        if !self.is_callable(&func) {
+            let info = format!("method '{}'", method);
+            return Err(self.type_error(&func, "call", Some(info)));
+        }
+        self.call_function(func, evaluated_args)
+    }
+
+    /// Whether a value is a function or has a `__call` metamethod.
+    fn is_callable(&self, value: &Value) -> bool {
+        matches!(value, Value::Function(_)) || self.get_metamethod(value, "__call") != Value::Nil
+    }
+
+    pub fn call_function(&mut self, func: Value, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+        if stack_pointer() < self.stack_limit {
+            return self.on_new_segment(|vm| vm.call_value(func, args));
+        }
+        self.call_value(func, args)
+    }
+
+    /// Runs `f` on a new stack segment, the one in use being nearly
+    /// exhausted.
+    fn on_new_segment<T>(&mut self, f: impl FnOnce(&mut Self) -> LuaResult<T>) -> LuaResult<T> {
+        if self.segments == MAX_STACK_SEGMENTS {
+            return Err(self.runtime_error("stack overflow"));
+        }
+        let mut segment = match self.spare_segment.take() {
+            Some(segment) => segment,
+            None => DefaultStack::new(STACK_SEGMENT_SIZE)
+                .map_err(|_| LuaError::message("not enough memory"))?,
+        };
+        let limit = std::mem::replace(&mut self.stack_limit, stack_limit(&segment));
+        self.segments += 1;
+        let result = corosensei::on_stack(&mut segment, || f(self));
+        self.segments -= 1;
+        self.stack_limit = limit;
+        self.spare_segment = Some(segment);
+        result
+    }
+
+    /// Runs `f` on a stack of its own rather than the caller's, which may
+    /// be too small for Lua code, growing it as calls nest. Without memory
+    /// for one, `f` runs on the caller's stack.
+    fn on_lua_stack<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let Ok(mut stack) = DefaultStack::new(STACK_SEGMENT_SIZE) else {
+            return f(self);
+        };
+        let limit = std::mem::replace(&mut self.stack_limit, stack_limit(&stack));
+        let segments = std::mem::replace(&mut self.segments, 1);
+        let result = corosensei::on_stack(&mut stack, || f(self));
+        self.segments = segments;
+        self.stack_limit = limit;
+        result
+    }
+
+    fn call_value(&mut self, func: Value, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+        match func {
+            Value::Function(Function::Native(native_func)) => native_func(self, args),
+            Value::Function(Function::NativeClosure { function, upvalues }) => {
+                let mut call_args = Vec::clone(&upvalues);
+                call_args.extend(args);
+                function(self, call_args)
+            }
+            Value::Function(Function::UserDefined { function, closure }) => {
+                self.execute_user_function(&function, closure, args)
+            }
+            // Other values are callable through `__call`, which receives the
+            // called value before the arguments
+            value => {
+                let handler = self.get_metamethod(&value, "__call");
+                if handler == Value::Nil {
+                    return Err(self.type_error(&value, "call", None));
+                }
+                let mut call_args = vec![value];
+                call_args.extend(args);
+                self.call_function(handler, call_args)
+            }
+        }
+    }
+
+    /// Runs a coroutine until it yields, returns or fails.
+    fn resume_coroutine(
+        &mut self,
+        coroutine: &Rc<Coroutine>,
+        args: Vec<Value>,
+    ) -> LuaResult<Vec<Value>> {
+        match coroutine.status() {
+            CoroutineStatus::Suspended => {}
+            CoroutineStatus::Dead => return Err(LuaError::message("cannot resume dead coroutine")),
+            _ => return Err(LuaError::message("cannot resume non-suspended coroutine")),
+        }
+
+        let resumer = self.running_coroutine();
+        resumer.set_status(CoroutineStatus::Normal);
+        coroutine.set_status(CoroutineStatus::Running);
+        self.coroutines.borrow_mut().push(coroutine.clone());
+
+        let outcome = coroutine.resume(self, args);
+
+        self.coroutines.borrow_mut().pop();
+        resumer.set_status(CoroutineStatus::Running);
+        match outcome {
+            Outcome::Yielded(values) | Outcome::Returned(values) => Ok(values),
+            Outcome::Failed(error) => Err(error),
+        }
+    }
+
+    /// Suspends the running coroutine, handing `values` to its resumer, and
+    /// returns the arguments of the resume that continues it.
+    fn yield_values(&mut self, values: Vec<Value>) -> LuaResult<Vec<Value>> {
+        let Some(yielder) = self.yielder else {
+            return Err(LuaError::message(
+                "attempt to yield from outside a coroutine",
+            ));
+        };
+        // The suspended stack must not hold the coroutine itself, or it
+        // would keep itself alive
+        self.running_coroutine()
+            .suspend_frames(std::mem::take(&mut self.call_stack));
+        let args = yielder.suspend(values);
+        self.call_stack = self.running_coroutine().take_frames();
+        Ok(args)
+    }
+
+    fn running_coroutine(&self) -> Rc<Coroutine> {
+        self.coroutines
+            .borrow()
+            .last()
+            .cloned()
+            .expect("main thread")
+    }
+
+    fn execute_user_function(
+        &mut self,
+        function: &FunctionBody,
+        closure: Rc<HashMap<String, Variable>>,
+        mut args: Vec<Value>,
+    ) -> LuaResult<Vec<Value>> {
+        let mut locals = HashMap::clone(&closure);
+
+        let varargs = if function.is_vararg && args.len() > function.parameters.len() {
+            args.split_off(function.parameters.len())
+        } else {
+            Vec::new()
+        };
+        // The arguments move into the parameters, so that nothing but the
+        // frame holds them while the function runs
+        let mut args = args.into_iter();
+        for param in &function.parameters {
+            let value = args.next().unwrap_or(Value::Nil);
+            let variable = Rc::new(RefCell::new(value));
+            locals.insert(param.clone(), variable);
+        }
+
+        self.call_stack.push(CallFrame {
+            locals,
+            upvalues: closure,
+            varargs,
+            shadowed: Vec::new(),
+            to_close: Vec::new(),
+            source: function.source.clone(),
+            line: 0,
+        });
+
+        let flow = self.execute_block(&function.body);
+
+        self.call_stack.pop();
+
+        match flow? {
+            ControlFlow::Return(values) => Ok(values),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    fn evaluate_table_access(&mut self, table: &Expr, key: &Expr) -> LuaResult<Value> {
+        let table_val = self.evaluate_expr(table)?;
+        let key_val = self.evaluate_expr(key)?;
+        self.check_indexable(&table_val, table)?;
+        self.index_value(&table_val, &key_val)
+    }
+
+    /// Fails unless a value is a table, naming the variable the indexed
+    /// expression reads. Only tables can be indexed, with or without a
+    /// metatable.
+    fn check_indexable(&self, value: &Value, expr: &Expr) -> LuaResult<()> {
+        match value {
+            Value::Table(_) => Ok(()),
+            _ => Err(self.type_error(value, "index", self.variable_info(expr))),
+        }
+    }
+
+    /// `table[key]`. A missing field is looked up through `__index`, which
+    /// is either called with the table and key or indexed in turn.
+    fn index_value(&mut self, table: &Value, key: &Value) -> LuaResult<Value> {
+        let mut current = table.clone();
+        for _ in 0..MAX_META_CHAIN {
+            if let Value::Table(t) = &current {
+                let value = t.borrow().get(key);
+                if value != Value::Nil {
+                    return Ok(value);
+                }
+            }
+            match self.get_metamethod(&current, "__index") {
+                Value::Nil if matches!(current, Value::Table(_)) => return Ok(Value::Nil),
+                Value::Nil => return Err(self.type_error(&current, "index", None)),
+                handler @ Value::Function(_) => {
+                    return Ok(first(
+                        self.call_function(handler, vec![current, key.clone()])?,
+                    ))
+                }
+                handler => current = handler,
+            }
+        }
+        Err(self.runtime_error("'__index' chain too long; possible loop"))
+    }
+
+    /// Builds a table. Fields are evaluated in order, but like the reference
+    /// implementation the positional ones are stored last, so they win over
+    /// explicit keys for the same index.
+    fn evaluate_table_constructor(&mut self, fields: &[TableField]) -> LuaResult<Value> {
+        let mut entries = Vec::new();
+        let mut positional = Vec::new();
+        for (i, field) in fields.iter().enumerate() {
+            match field {
+                TableField::Value(expr) if i == fields.len() - 1 => {
+                    // A trailing call fills every remaining array slot
+                    positional.extend(self.evaluate_multi(expr)?);
+                }
+                TableField::Value(expr) => {
+                    positional.push(self.evaluate_expr(expr)?);
+                }
+                TableField::KeyValue(key, expr) => {
+                    let value = self.evaluate_expr(expr)?;
+                    entries.push((Value::String(key.clone()), value));
+                }
+                TableField::ComputedKey(key, expr) => {
+                    let key = self.evaluate_expr(key)?;
+                    let value = self.evaluate_expr(expr)?;
+                    entries.push((key, value));
+                }
+            }
+        }
+
+        let table = LuaTable::with_array_size(positional.len());
+        let table = Rc::new(RefCell::new(table));
+        self.collector.borrow_mut().track_table(&table);
+        let table = Value::Table(table);
+        for (key, value) in entries {
+            self.set_index(&table, key, value)?;
+        }
+        for (i, value) in positional.into_iter().enumerate() {
+            self.set_index(&table, Value::Integer(i as i64 + 1), value)?;
+        }
+        Ok(table)
+    }
+
+    /// Evaluates an expression that may produce several values, such as a
+    /// call in the last position of a list.
+    fn evaluate_multi(&mut self, expr: &Expr) -> LuaResult<Vec<Value>> {
+        match expr {
+            Expr::FunctionCall { callee, arguments } => {
+                self.evaluate_function_call(callee, arguments)
+            }
+            Expr::MethodCall {
+                object,
+                method,
+                arguments,
+            } => self.evaluate_method_call(object, method, arguments),
+            Expr::Vararg => Ok(self
+                .call_stack
+                .last()
+                .map(|frame| frame.varargs.clone())
+                .unwrap_or_default()),
+            _ => Ok(vec![self.evaluate_expr(expr)?]),
+        }
+    }
+
+    /// Evaluates a list of expressions left to right. Only the last one may
+    /// contribute several values; the others are truncated to one.
+    fn evaluate_expr_list(&mut self, exprs: &[Expr]) -> LuaResult<Vec<Value>> {
+        let mut values = Vec::with_capacity(exprs.len());
+        if let Some((last, init)) = exprs.split_last() {
+            for expr in init {
+                values.push(self.evaluate_expr(expr)?);
+            }
+            values.extend(self.evaluate_multi(last)?);
+        }
+        Ok(values)
+    }
+
+    /// The position of the function at `level` of the call stack, as a
+    /// `chunk:line:` prefix for error messages. Level 1 is the running Lua
+    /// function, which for a builtin is the one that called it. Empty when
+    /// there is no such function.
+    fn position(&self, level: usize) -> String {
+        let frame = self
+            .call_stack
+            .len()
+            .checked_sub(level)
+            .and_then(|i| self.call_stack.get(i));
+        match frame {
+            Some(frame) if frame.line > 0 => format!("{}:{}: ", frame.source, frame.line),
+            _ => String::new(),
+        }
+    }
+
+    /// An error raised by the interpreter or a builtin, with the position
+    /// of the Lua code that caused it.
+    fn runtime_error(&self, message: &str) -> LuaError {
+        LuaError::message(format!("{}{}", self.position(1), message))
+    }
+
+    /// The error for a value that does not support an operation, such as
+    /// "attempt to call a nil value (global 'f')".
+    fn type_error(&self, value: &Value, action: &str, info: Option<String>) -> LuaError {
+        self.runtime_error(&format!(
+            "attempt to {} a {} value{}",
+            action,
+            value.type_name(),
+            describe(info)
+        ))
+    }
+
+    /// What kind of variable an expression reads and its name, such as
+    /// "local 'x'", which error messages mention to point at the culprit.
+    /// None for expressions that are not variables.
+    fn variable_info(&self, expr: &Expr) -> Option<String> {
+        let (kind, name) = match expr {
+            Expr::Identifier(name) => match self.lookup_local(name) {
+                Some(variable) if self.is_upvalue(name, &variable) => ("upvalue", name.as_str()),
+                Some(_) => ("local", name.as_str()),
+                None => ("global", name.as_str()),
+            },
+            Expr::TableAccess { key, .. } => match key.as_ref() {
+                Expr::String(name) => ("field", name.as_str()),
+                _ => ("field", "?"),
+            },
+            Expr::String(s) => ("constant", s.as_str()),
+            Expr::Paren(inner) => return self.variable_info(inner),
+            _ => return None,
+        };
+        Some(format!("{} '{}'", kind, name))
+    }
+}
+
+/// Formats variable info to follow a message, in parentheses.
+fn describe(info: Option<String>) -> String {
+    info.map_or(String::new(), |info| format!(" ({})", info))
+}
+
+/// The first of the results of a call, or nil when there are none.
+fn first(values: Vec<Value>) -> Value {
+    values.into_iter().next().unwrap_or(Value::Nil)
+}
+
+fn print(vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let output = args
+        .iter()
+        .map(|v| vm.tostring(v))
+        .collect::<LuaResult<Vec<String>>>()?;
+    println!("{}", output.join("\t"));
+    Ok(Vec::new())
+}
+
+fn type_of(_vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    if args.len() != 1 {
+        return Ok(vec![Value::Nil]);
+    }
 
-    Value::String(type_name.to_string())
+    Ok(vec![Value::String(args[0].type_name().to_string())])
 }
 
-fn to_number(_vm: &mut Vm, args: Vec<Value>) -> Value {
+fn to_number(_vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
     if args.is_empty() {
-        return Value::Nil;
+        return Ok(vec![Value::Nil]);
     }
 
-    args[0].to_number().map(Value::Number).unwrap_or(Value::Nil)
+    Ok(vec![args[0].coerce_number().unwrap_or(Value::Nil)])
 }
 
-fn to_string(_vm: &mut Vm, args: Vec<Value>) -> Value {
+fn to_string(vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
     if args.is_empty() {
-        return Value::String("".to_string());
+        return Ok(vec![Value::String("".to_string())]);
     }
 
-    Value::String(args[0].to_string())
+    Ok(vec![Value::String(vm.tostring(&args[0])?)])
+}
+
+fn set_metatable(vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let mut args = args.into_iter();
+    let table = args.next().unwrap_or(Value::Nil);
+    let t = match &table {
+        Value::Table(t) => t.clone(),
+        other => {
+            return Err(vm.runtime_error(&format!(
+                "bad argument #1 to 'setmetatable' (table expected, got {})",
+                other.type_name()
+            )))
+        }
+    };
+    let metatable = match args.next() {
+        None | Some(Value::Nil) => None,
+        Some(Value::Table(metatable)) => Some(metatable),
+        Some(other) => {
+            return Err(vm.runtime_error(&format!(
+                "bad argument #2 to 'setmetatable' (nil or table expected, got {})",
+                other.type_name()
+            )))
+        }
+    };
+    if vm.get_metamethod(&table, "__metatable") != Value::Nil {
+        return Err(vm.runtime_error("cannot change a protected metatable"));
+    }
+    // Like in the reference implementation, a table is only marked for
+    // finalization if its metatable has a `__gc` field when it is set
+    let finalizer = metatable.as_ref().map_or(Value::Nil, |metatable| {
+        metatable.borrow().get(&Value::String("__gc".to_string()))
+    });
+    t.borrow_mut().set_metatable(metatable);
+    if finalizer != Value::Nil {
+        vm.collector.borrow_mut().track_finalizer(&t);
+    }
+    Ok(vec![table])
+}
+
+/// The metatable of a value, or the `__metatable` field of the metatable
+/// when it has one, which hides the real metatable.
+fn get_metatable(_vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let metatable = match args.first() {
+        Some(Value::Table(t)) => t.borrow().metatable(),
+        _ => None,
+    };
+    let Some(metatable) = metatable else {
+        return Ok(vec![Value::Nil]);
+    };
+    let protected = metatable
+        .borrow()
+        .get(&Value::String("__metatable".to_string()));
+    if protected != Value::Nil {
+        Ok(vec![protected])
+    } else {
+        Ok(vec![Value::Table(metatable)])
+    }
+}
+
+fn raw_get(vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    match args.first() {
+        Some(Value::Table(t)) => Ok(vec![t.borrow().get(args.get(1).unwrap_or(&Value::Nil))]),
+        _ => Err(vm.runtime_error("bad argument #1 to 'rawget' (table expected)")),
+    }
+}
+
+fn raw_set(vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let mut args = args.into_iter();
+    let table = args.next().unwrap_or(Value::Nil);
+    let Value::Table(t) = &table else {
+        return Err(vm.runtime_error("bad argument #1 to 'rawset' (table expected)"));
+    };
+    let key = args.next().unwrap_or(Value::Nil);
+    let value = args.next().unwrap_or(Value::Nil);
+    let result = t.borrow_mut().set(key, value);
+    if let Err(message) = result {
+        return Err(vm.runtime_error(message));
+    }
+    Ok(vec![table])
+}
+
+fn raw_equal(_vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let left = args.first().unwrap_or(&Value::Nil);
+    let right = args.get(1).unwrap_or(&Value::Nil);
+    Ok(vec![left.equal(right)])
+}
+
+fn raw_len(vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    match args.first() {
+        Some(value @ (Value::Table(_) | Value::String(_))) => Ok(vec![value.length()]),
+        _ => Err(vm.runtime_error("table or string expected")),
+    }
+}
+
+/// Controls the collector. Collections always run to completion: the
+/// modes only change which collection is due and when, and "step" runs
+/// that collection, telling whether it was a full one. "incremental"
+/// selects full collections; its step parameters are accepted but have
+/// no effect.
+fn collect_garbage(vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let option = match args.first() {
+        None | Some(Value::Nil) => "collect".to_string(),
+        Some(Value::String(option)) => option.clone(),
+        Some(value) => {
+            return Err(vm.runtime_error(&format!(
+                "bad argument #1 to 'collectgarbage' (string expected, got {})",
+                value.type_name()
+            )))
+        }
+    };
+    // Zero or a missing parameter keeps the current setting
+    let parameter = |i: usize| {
+        args.get(i)
+            .and_then(Value::to_integer)
+            .map_or(0, |n| n.max(0) as usize)
+    };
+    let result = match option.as_str() {
+        "collect" => {
+            vm.collector.borrow_mut().collect();
+            vm.run_finalizers();
+            Value::Integer(0)
+        }
+        "count" => Value::Number(vm.collector.borrow().allocated_bytes() as f64 / 1024.0),
+        "step" => {
+            let finished = vm.collector.borrow_mut().collect_due();
+            vm.run_finalizers();
+            Value::Boolean(finished)
+        }
+        "isrunning" => Value::Boolean(vm.collector.borrow().is_running()),
+        "stop" | "restart" => {
+            vm.collector.borrow_mut().set_running(option == "restart");
+            Value::Integer(0)
+        }
+        "incremental" | "generational" => {
+            let previous = vm.collector.borrow().mode();
+            if option == "incremental" {
+                vm.collector.borrow_mut().set_full(parameter(1));
+            } else {
+                vm.collector
+                    .borrow_mut()
+                    .set_generational(parameter(1), parameter(2));
+            }
+            Value::String(previous.name().to_string())
+        }
+        _ => {
+            return Err(vm.runtime_error(&format!(
+                "bad argument #1 to 'collectgarbage' (invalid option '{}')",
+                option
+            )))
+        }
+    };
+    Ok(vec![result])
+}
+
+/// Raises its first argument as an error. A message string gets the
+/// position of the function at `level` in the call stack: 1, the default,
+/// is the function that called `error`, and 0 adds no position.
+fn error(vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let mut args = args.into_iter();
+    let value = args.next().unwrap_or(Value::Nil);
+    let level = args
+        .next()
+        .and_then(|level| level.to_integer())
+        .unwrap_or(1);
+    match value {
+        Value::String(message) if level > 0 => Err(LuaError::message(format!(
+            "{}{}",
+            vm.position(level as usize),
+            message
+        ))),
+        value => Err(LuaError::new(value)),
+    }
+}
+
+/// Calls a function in protected mode: an error it raises is caught and
+/// returned after a false status instead of propagating.
+fn pcall(vm: &mut Vm, mut args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    if args.is_empty() {
+        return Err(vm.runtime_error("bad argument #1 to 'pcall' (value expected)"));
+    }
+    let rest = args.split_off(1);
+    let function = args.pop().unwrap_or(Value::Nil);
+    match vm.call_function(function, rest) {
+        Ok(mut values) => {
+            values.insert(0, Value::Boolean(true));
+            Ok(values)
+        }
+        Err(error) => Ok(vec![Value::Boolean(false), error.value]),
+    }
+}
+
+/// Like `pcall`, but an error goes through a message handler, and what it
+/// returns is the result after the false status. An error in the handler
+/// itself is returned as it is.
+fn xpcall(vm: &mut Vm, mut args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    if args.len() < 2 {
+        return Err(vm.runtime_error("bad argument #2 to 'xpcall' (value expected)"));
+    }
+    let rest = args.split_off(2);
+    let handler = args.pop().unwrap_or(Value::Nil);
+    let function = args.pop().unwrap_or(Value::Nil);
+    match vm.call_function(function, rest) {
+        Ok(mut values) => {
+            values.insert(0, Value::Boolean(true));
+            Ok(values)
+        }
+        Err(error) => {
+            let handled = match vm.call_function(handler, vec![error.value]) {
+                Ok(values) => first(values),
+                Err(error) => error.value,
+            };
+            Ok(vec![Value::Boolean(false), handled])
+        }
+    }
+}
+
+/// Returns its arguments when the first is true, and raises the second,
+/// or "assertion failed!", as an error otherwise. Unlike `error`, it adds
+/// no position to the message.
+fn assert(vm: &mut Vm, mut args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    match args.first() {
+        None => Err(vm.runtime_error("bad argument #1 to 'assert' (value expected)")),
+        Some(condition) if condition.is_truthy() => Ok(args),
+        Some(_) if args.len() > 1 => Err(LuaError::new(args.swap_remove(1))),
+        Some(_) => Err(LuaError::message("assertion failed!")),
+    }
+}
+
+fn select(_vm: &mut Vm, mut args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    if args.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let rest = args.split_off(1);
+    let selected = match &args[0] {
+        Value::String(s) if s == "#" => vec![Value::Integer(rest.len() as i64)],
+        index => match index.to_integer() {
+            // Negative indices count back from the last argument
+            Some(n) if n < 0 && n.unsigned_abs() <= rest.len() as u64 => {
+                rest[rest.len() - n.unsigned_abs() as usize..].to_vec()
+            }
+            Some(n) if n >= 1 => rest.into_iter().skip(n as usize - 1).collect(),
+            _ => Vec::new(),
+        },
+    };
+    Ok(selected)
+}
+
+fn next(vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let table = match args.first() {
+        Some(Value::Table(t)) => t.clone(),
+        other => {
+            return Err(vm.runtime_error(&format!(
+                "bad argument #1 to 'next' (table expected, got {})",
+                other.map_or("no value", Value::type_name)
+            )))
+        }
+    };
+    let key = args.get(1).cloned().unwrap_or(Value::Nil);
+
+    let next = table.borrow().next(&key);
+    match next {
+        Ok(Some((k, v))) => Ok(vec![k, v]),
+        Ok(None) => Ok(vec![Value::Nil]),
+        Err(message) => Err(vm.runtime_error(message)),
+    }
+}
+
+fn pairs(vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let table = args.into_iter().next().unwrap_or(Value::Nil);
+    // `__pairs` supplies the iterator triple instead
+    let handler = vm.get_metamethod(&table, "__pairs");
+    if handler != Value::Nil {
+        let mut results = vm.call_function(handler, vec![table])?;
+        results.resize(3, Value::Nil);
+        return Ok(results);
+    }
+    Ok(vec![
+        Value::Function(Function::Native(next)),
+        table,
+        Value::Nil,
+    ])
+}
+
+fn ipairs(_vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let table = args.into_iter().next().unwrap_or(Value::Nil);
+    Ok(vec![
+        Value::Function(Function::Native(ipairs_next)),
+        table,
+        Value::Integer(0),
+    ])
+}
+
+fn ipairs_next(vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let table = args.first().cloned().unwrap_or(Value::Nil);
+    let index = args.get(1).and_then(|i| i.to_integer()).unwrap_or(0) + 1;
+    let value = vm.index_value(&table, &Value::Integer(index))?;
+    if value == Value::Nil {
+        Ok(vec![Value::Nil])
+    } else {
+        Ok(vec![Value::Integer(index), value])
+    }
+}
+
+fn math_type(_vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let subtype = match args.first() {
+        Some(Value::Integer(_)) => "integer",
+        Some(Value::Number(_)) => "float",
+        _ => return Ok(vec![Value::Nil]),
+    };
+    Ok(vec![Value::String(subtype.to_string())])
+}
+
+fn math_to_integer(_vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let integer = args.first().and_then(|value| value.to_integer());
+    Ok(vec![integer.map_or(Value::Nil, Value::Integer)])
+}
+
+/// How many times an integer for loop steps after its first iteration, or
+/// None when the body never runs. Counting up front, like the reference
+/// implementation, keeps the control variable from overflowing.
+fn for_iteration_count(start: i64, limit: &Value, step: i64) -> Option<u64> {
+    let limit = match limit {
+        Value::Integer(n) => *n,
+        // A float limit is rounded towards the loop's direction; the cast
+        // saturates limits beyond the integer range
+        Value::Number(n) if !n.is_nan() => {
+            if step > 0 {
+                n.floor() as i64
+            } else {
+                n.ceil() as i64
+            }
+        }
+        _ => return None,
+    };
+    if step > 0 && start > limit || step < 0 && start < limit {
+        return None;
+    }
+    let count = if step > 0 {
+        (limit as u64).wrapping_sub(start as u64) / step as u64
+    } else {
+        // -(step + 1) + 1 is the magnitude of the step without overflowing
+        (start as u64).wrapping_sub(limit as u64) / ((-(step + 1)) as u64 + 1)
+    };
+    Some(count)
+}
+
+/// Roughly where the top of the stack is.
+fn stack_pointer() -> usize {
+    let marker = 0u8;
+    std::hint::black_box(&marker) as *const u8 as usize
+}
+
+/// The address below which `stack` is nearly exhausted.
+fn stack_limit(stack: &DefaultStack) -> usize {
+    stack.limit().get() + STACK_RESERVE
+}
+
+fn coroutine_argument(vm: &Vm, args: &[Value], function: &str) -> LuaResult<Rc<Coroutine>> {
+    match args.first() {
+        Some(Value::Thread(co)) => Ok(co.clone()),
+        _ => Err(vm.runtime_error(&format!(
+            "bad argument #1 to '{}' (coroutine expected)",
+            function
+        ))),
+    }
+}
+
+fn coroutine_create(vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    match args.into_iter().next() {
+        Some(function @ Value::Function(_)) => {
+            let co = Rc::new(Coroutine::new(function));
+            vm.collector.borrow_mut().track_thread(&co);
+            Ok(vec![Value::Thread(co)])
+        }
+        _ => Err(vm.runtime_error("bad argument #1 to 'create' (function expected)")),
+    }
+}
+
+fn coroutine_resume(vm: &mut Vm, mut args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let co = coroutine_argument(vm, &args, "resume")?;
+    match vm.resume_coroutine(&co, args.split_off(1)) {
+        Ok(mut values) => {
+            values.insert(0, Value::Boolean(true));
+            Ok(values)
+        }
+        Err(error) => Ok(vec![Value::Boolean(false), error.value]),
+    }
+}
+
+fn coroutine_yield(vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    vm.yield_values(args)
+}
+
+fn coroutine_status(vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let co = coroutine_argument(vm, &args, "status")?;
+    Ok(vec![Value::String(co.status().name().to_string())])
+}
+
+/// Creates one coroutine and a function resuming it each time it is
+/// called, returning what it yields and raising its errors.
+fn coroutine_wrap(vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let co = Rc::new(coroutine_create(vm, args)?);
+    vm.collector.borrow_mut().track_upvalues(&co);
+    Ok(vec![Value::Function(Function::NativeClosure {
+        function: resume_wrapped,
+        upvalues: co,
+    })])
+}
+
+/// Resumes the coroutine of a `coroutine.wrap` function. Its errors
+/// propagate as they are, already carrying the position they were raised
+/// at.
+fn resume_wrapped(vm: &mut Vm, mut args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let co = coroutine_argument(vm, &args, "wrap")?;
+    vm.resume_coroutine(&co, args.split_off(1))
+}
+
+/// Whether a coroutine, by default the running one, may yield: any but
+/// the main thread can.
+fn coroutine_is_yieldable(vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let yieldable = match args.first() {
+        Some(Value::Thread(co)) => !Rc::ptr_eq(co, &vm.coroutines.borrow()[0]),
+        _ => vm.yielder.is_some(),
+    };
+    Ok(vec![Value::Boolean(yieldable)])
+}
+
+/// The running coroutine, and whether it is the main thread.
+fn coroutine_running(vm: &mut Vm, _args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let co = vm.running_coroutine();
+    Ok(vec![
+        Value::Thread(co),
+        Value::Boolean(vm.yielder.is_none()),
+    ])
+}
+
+/// Kills a suspended or dead coroutine, closing the to-be-closed variables
+/// it was suspended with. Reports the error that killed it, or else the
+/// last error raised while closing them, if any.
+fn coroutine_close(vm: &mut Vm, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let co = coroutine_argument(vm, &args, "close")?;
+    match co.status() {
+        CoroutineStatus::Suspended | CoroutineStatus::Dead => {}
+        status => {
+            return Err(vm.runtime_error(&format!("cannot close a {} coroutine", status.name())))
+        }
+    }
+    let (frames, mut error) = co.close();
+    // Each frame is closed in turn as the current one, innermost first
+    for frame in frames.into_iter().rev() {
+        vm.call_stack.push(frame);
+        error = vm.close_variables(0, error).err();
+        vm.call_stack.pop();
+    }
+    match error {
+        Some(error) => Ok(vec![Value::Boolean(false), error.value]),
+        None => Ok(vec![Value::Boolean(true)]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Vm;
+    use crate::value::Value;
+    use crate::{run_chunk, run_lines};
+
+    fn integers(values: &[i64]) -> Result<Vec<Value>, String> {
+        Ok(values.iter().map(|&n| Value::Integer(n)).collect())
+    }
+
+    #[test]
+    fn anonymous_functions_are_first_class_values() {
+        let results = run_chunk(
+            "local function apply(f, x) return f(x) end
+             local double = function(n) return n * 2 end
+             return apply(double, 21), apply(function(n) return -n end, 1)",
+        );
+        assert_eq!(results, integers(&[42, -1]));
+    }
+
+    #[test]
+    fn closures_share_their_captured_variables() {
+        let results = run_chunk(
+            "local function counter()
+                 local n = 0
+                 return function() n = n + 1 return n end, function() return n end
+             end
+             local bump, read = counter()
+             local other = counter()
+             bump() bump()
+             return read(), other()",
+        );
+        assert_eq!(results, integers(&[2, 1]));
+    }
+
+    #[test]
+    fn each_loop_iteration_gets_a_fresh_binding() {
+        let results = run_chunk(
+            "local fs = {}
+             for i = 1, 3 do fs[i] = function() return i end end
+             return fs[1](), fs[2](), fs[3]()",
+        );
+        assert_eq!(results, integers(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn callees_cannot_see_their_callers_locals() {
+        let results = run_chunk(
+            "function peek() return secret end
+             local function caller() local secret = 1 return peek() end
+             return caller()",
+        );
+        assert_eq!(results, Ok(vec![Value::Nil]));
+    }
+
+    #[test]
+    fn block_locals_shadow_and_then_restore_outer_ones() {
+        let results = run_chunk(
+            "local x = 1
+             local inner
+             do local x = 2 inner = x end
+             if true then local y = 5 end
+             return x, inner, y",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![Value::Integer(1), Value::Integer(2), Value::Nil])
+        );
+    }
+
+    #[test]
+    fn loop_variables_are_scoped_to_the_loop_body() {
+        let results = run_chunk(
+            "local i = 10
+             for i = 1, 2 do end
+             while true do local z = 3 break end
+             return i, z",
+        );
+        assert_eq!(results, Ok(vec![Value::Integer(10), Value::Nil]));
+    }
+
+    #[test]
+    fn closed_interactive_locals_are_gone_on_the_next_line() {
+        let results = run_lines(&[
+            "local x = 'outer'",
+            "local closable = setmetatable({}, {__close = function() closed = true end})",
+            "local x <close> = closable",
+            "return x, closed",
+        ]);
+        assert_eq!(
+            results[3],
+            Ok(vec![
+                Value::String("outer".to_string()),
+                Value::Boolean(true)
+            ])
+        );
+    }
+
+    #[test]
+    fn break_leaves_only_the_innermost_loop() {
+        let results = run_chunk(
+            "local count = 0
+             for i = 1, 3 do
+                 while true do count = count + 1 break end
+                 repeat break until false
+             end
+             return count",
+        );
+        assert_eq!(results, integers(&[3]));
+    }
+
+    #[test]
+    fn return_stops_the_function_from_inside_loops() {
+        let results = run_chunk(
+            "local function find(t, wanted)
+                 for i = 1, #t do
+                     if t[i] == wanted then return i end
+                 end
+                 return nil
+             end
+             return find({5, 6, 7}, 6), find({5}, 1)",
+        );
+        assert_eq!(results, Ok(vec![Value::Integer(2), Value::Nil]));
+    }
+
+    #[test]
+    fn break_outside_a_loop_is_rejected_when_parsing() {
+        assert_eq!(
+            run_chunk("local function f() break end"),
+            Err("test:1: break outside a loop near 'break'".to_string())
+        );
+    }
+
+    #[test]
+    fn calls_spread_only_in_the_last_position() {
+        let results = run_chunk(
+            "local function two() return 1, 2 end
+             local a, b, c = two()
+             local x, y = (two())
+             local t = {two(), two()}
+             return a, b, c, x, y, #t",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Integer(1),
+                Value::Integer(2),
+                Value::Nil,
+                Value::Integer(1),
+                Value::Nil,
+                Value::Integer(3),
+            ])
+        );
+    }
+
+    #[test]
+    fn multiple_assignment_evaluates_before_assigning() {
+        let results = run_chunk(
+            "local p, q = 1, 2
+             p, q = q, p
+             return p, q",
+        );
+        assert_eq!(results, integers(&[2, 1]));
+    }
+
+    #[test]
+    fn assignment_targets_fields_indexes_and_upvalues() {
+        let results = run_chunk(
+            "local o = {a = {}}
+             local n = 0
+             local function bump() n = n + 1 end
+             o.a.b, o['c'], g = 1, 2, 3
+             bump()
+             return o.a.b, o.c, g, n",
+        );
+        assert_eq!(results, integers(&[1, 2, 3, 1]));
+    }
+
+    #[test]
+    fn index_expressions_are_evaluated_before_assigning() {
+        let results = run_chunk(
+            "local t = {}
+             local i = 1
+             i, t[i] = i + 1, 20
+             return i, t[1], t[2]",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![Value::Integer(2), Value::Integer(20), Value::Nil])
+        );
+    }
+
+    #[test]
+    fn varargs_expand_and_count_trailing_nils() {
+        let results = run_chunk(
+            "local function f(first, ...)
+                 local n = select('#', ...)
+                 return n, #{...}, first, ...
+             end
+             return f(1, 2, nil, 4)",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Integer(3),
+                Value::Integer(3),
+                Value::Integer(1),
+                Value::Integer(2),
+                Value::Nil,
+                Value::Integer(4),
+            ])
+        );
+        assert_eq!(
+            run_chunk("return select(-1, 'a', 'b'), select(2, 'a', 'b')"),
+            Ok(vec![
+                Value::String("b".to_string()),
+                Value::String("b".to_string())
+            ])
+        );
+    }
+
+    #[test]
+    fn the_main_chunk_receives_the_script_arguments() {
+        let chunk = crate::parse_source("return ...".to_string(), "test").unwrap();
+        let args = vec![Value::String("a".to_string()), Value::Integer(2)];
+        let results = Vm::new()
+            .execute(chunk, args.clone())
+            .map_err(|e| e.to_string());
+        assert_eq!(results, Ok(args));
+    }
+
+    #[test]
+    fn varargs_outside_a_vararg_function_are_rejected() {
+        assert_eq!(
+            run_chunk("function f() return ... end"),
+            Err("test:1: cannot use '...' outside a vararg function near '...'".to_string())
+        );
+    }
+
+    #[test]
+    fn generic_for_drives_the_iterator_protocol() {
+        let results = run_chunk(
+            "local function range(n)
+                 return function(limit, i) if i < limit then return i + 1 end end, n, 0
+             end
+             local sum = 0
+             for i in range(4) do sum = sum + i end
+             return sum",
+        );
+        assert_eq!(results, integers(&[10]));
+    }
+
+    #[test]
+    fn ipairs_stops_at_the_first_nil_and_pairs_visits_every_key() {
+        let results = run_chunk(
+            "local seen = 0
+             for _ in ipairs({1, 2, nil, 4}) do seen = seen + 1 end
+             local keys = 0
+             for k, v in pairs({1, 2, x = 3, y = 4}) do keys = keys + 1 end
+             return seen, keys, next({})",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![Value::Integer(2), Value::Integer(4), Value::Nil])
+        );
+    }
+
+    #[test]
+    fn and_or_return_the_deciding_operand() {
+        let results = run_chunk("return nil or 'default', false and 1, 1 and 2, false or nil");
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::String("default".to_string()),
+                Value::Boolean(false),
+                Value::Integer(2),
+                Value::Nil,
+            ])
+        );
+    }
+
+    #[test]
+    fn and_or_skip_the_right_operand_when_decided() {
+        let results = run_chunk(
+            "local calls = 0
+             local function touch() calls = calls + 1 return true end
+             local _ = false and touch()
+             _ = true or touch()
+             _ = true and touch()
+             return calls",
+        );
+        assert_eq!(results, integers(&[1]));
+    }
+
+    #[test]
+    fn metamethods_customise_operators() {
+        let results = run_chunk(
+            "local V = {}
+             V.__index = V
+             V.__add = function(a, b) return V.new(a.x + b.x) end
+             V.__eq = function(a, b) return a.x == b.x end
+             V.__lt = function(a, b) return a.x < b.x end
+             V.__len = function() return 42 end
+             V.__call = function(self, y) return self.x + y end
+             V.__tostring = function(self) return 'V(' .. self.x .. ')' end
+             function V.new(x) return setmetatable({x = x}, V) end
+             function V:get() return self.x end
+             local a, b = V.new(1), V.new(2)
+             return (a + b):get(), a == V.new(1), a < b, #a, a(10), tostring(a)",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Integer(3),
+                Value::Boolean(true),
+                Value::Boolean(true),
+                Value::Integer(42),
+                Value::Integer(11),
+                Value::String("V(1)".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn index_and_newindex_handle_missing_fields() {
+        let results = run_chunk(
+            "local p = setmetatable({}, {
+                 __newindex = function(t, k, v) rawset(t, k, v * 2) end,
+                 __index = function(t, k) return k .. '!' end,
+             })
+             p.x = 5
+             return p.x, p.y, rawget(p, 'y')",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Integer(10),
+                Value::String("y!".to_string()),
+                Value::Nil,
+            ])
+        );
+    }
+
+    #[test]
+    fn protected_metatables_cannot_be_replaced() {
+        let results = run_chunk(
+            "local locked = setmetatable({}, {__metatable = 'locked'})
+             return getmetatable(locked), pcall(setmetatable, locked, {})",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::String("locked".to_string()),
+                Value::Boolean(false),
+                Value::String("test:2: cannot change a protected metatable".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn close_handlers_run_however_a_block_is_left() {
+        let results = run_chunk(
+            "local log = {}
+             local function closer(name)
+               return setmetatable({}, {__close = function(_, err)
+                 log[#log + 1] = name .. ':' .. tostring(err)
+               end})
+             end
+             do
+               local a <close> = closer('end')
+             end
+             while true do
+               local b <close> = closer('break')
+               break
+             end
+             local function f()
+               local c <close> = closer('return')
+               return 1
+             end
+             f()
+             pcall(function()
+               local d <close> = closer('error')
+               error('boom', 0)
+             end)
+             return log[1], log[2], log[3], log[4]",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::String("end:nil".to_string()),
+                Value::String("break:nil".to_string()),
+                Value::String("return:nil".to_string()),
+                Value::String("error:boom".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn close_handlers_run_in_reverse_order_of_declaration() {
+        let results = run_chunk(
+            "local order = ''
+             do
+               local mt = {__close = function(v) order = order .. v[1] end}
+               local a <close> = setmetatable({'a'}, mt)
+               local b <close> = setmetatable({'b'}, mt)
+               local c <close> = false
+             end
+             return order",
+        );
+        assert_eq!(results, Ok(vec![Value::String("ba".to_string())]));
+        assert_eq!(
+            run_chunk("local x <close> = {}"),
+            Err("test:1: variable 'x' got a non-closable value".to_string())
+        );
+    }
+
+    #[test]
+    fn finalizers_run_once_their_table_is_unreachable() {
+        let results = run_chunk(
+            "local log = {}
+             local function tracked(name)
+               return setmetatable({}, {__gc = function() log[#log + 1] = name end})
+             end
+             local kept = tracked('kept')
+             tracked('first')
+             tracked('second')
+             collectgarbage()
+             return #log, log[1], log[2]",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Integer(2),
+                Value::String("second".to_string()),
+                Value::String("first".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn finalizers_of_live_tables_run_when_the_interpreter_closes() {
+        let chunk = crate::parse_source(
+            "finalized = {}
+             keep = setmetatable({}, {__gc = function() finalized[1] = true end})"
+                .to_string(),
+            "test",
+        )
+        .unwrap();
+        let mut vm = Vm::new();
+        vm.execute(chunk, Vec::new()).unwrap();
+        vm.close();
+        let finalized = vm.globals.borrow()["finalized"].clone();
+        let Value::Table(finalized) = finalized else {
+            panic!("finalized is not a table");
+        };
+        assert_eq!(
+            finalized.borrow().get(&Value::Integer(1)),
+            Value::Boolean(true)
+        );
+    }
+
+    #[test]
+    fn deep_recursion_is_not_capped_below_the_stack() {
+        let results = run_chunk(
+            "local function depth(n) if n == 0 then return 0 end return 1 + depth(n - 1) end
+             return pcall(depth, 10000)",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![Value::Boolean(true), Value::Integer(10000)])
+        );
+    }
+
+    #[test]
+    fn runaway_recursion_is_a_catchable_error() {
+        let results = run_chunk(
+            "local function down(n) return 1 + down(n + 1) end
+             local ok, message = pcall(down, 1)
+             local call = setmetatable({}, {})
+             getmetatable(call).__call = function(self) return self() end
+             local called, called_message = pcall(call)
+             local wrapped = coroutine.wrap(function() return pcall(down, 1) end)
+             return ok, message, called, called_message, wrapped()",
+        );
+        let overflow = Value::String("test:1: stack overflow".to_string());
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Boolean(false),
+                overflow.clone(),
+                Value::Boolean(false),
+                Value::String("test:4: stack overflow".to_string()),
+                Value::Boolean(false),
+                overflow,
+            ])
+        );
+    }
+
+    #[test]
+    fn assert_raises_its_message_unchanged() {
+        let results = run_chunk(
+            "local t = {}
+             local _, plain = pcall(assert, false, 'plain message')
+             local _, default = pcall(assert, nil)
+             local _, value = pcall(assert, false, t)
+             return plain, default, value == t, assert(1, 2, 3)",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::String("plain message".to_string()),
+                Value::String("assertion failed!".to_string()),
+                Value::Boolean(true),
+                Value::Integer(1),
+                Value::Integer(2),
+                Value::Integer(3),
+            ])
+        );
+    }
+
+    #[test]
+    fn error_positions_follow_the_level() {
+        let results = run_chunk(
+            "local function fail(level) error('bad', level) end
+             local function caller() fail(2) end
+             local _, one = pcall(fail, 1)
+             local _, two = pcall(caller)
+             local _, none = pcall(fail, 0)
+             return one, two, none",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::String("test:1: bad".to_string()),
+                Value::String("test:2: bad".to_string()),
+                Value::String("bad".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn pcall_catches_errors_with_any_value() {
+        let results = run_chunk(
+            "local t = {code = 42}
+             local ok, value = pcall(error, t)
+             local fine, a, b = pcall(function(x, y) return y, x end, 1, 2)
+             return ok, value.code, fine, a, b, pcall(error)",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::Boolean(false),
+                Value::Integer(42),
+                Value::Boolean(true),
+                Value::Integer(2),
+                Value::Integer(1),
+                Value::Boolean(false),
+                Value::Nil,
+            ])
+        );
+    }
+
+    #[test]
+    fn runtime_errors_are_catchable() {
+        let results = run_chunk(
+            "local _, call = pcall(function() local x = nil; x() end)
+             local _, arith = pcall(function() return {} + 1 end)
+             return call, arith",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::String("test:1: attempt to call a nil value (local 'x')".to_string()),
+                Value::String("test:2: attempt to perform arithmetic on a table value".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn xpcall_passes_errors_through_the_handler() {
+        let results = run_chunk(
+            "local function handler(message) return 'handled: ' .. message end
+             local _, handled = xpcall(function() error('oops', 0) end, handler)
+             local ok, value = xpcall(function(a) return a end, handler, 7)
+             local _, broken = xpcall(error, function() error('again', 0) end)
+             return handled, ok, value, broken",
+        );
+        assert_eq!(
+            results,
+            Ok(vec![
+                Value::String("handled: oops".to_string()),
+                Value::Boolean(true),
+                Value::Integer(7),
+                Value::String("again".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn uncaught_errors_stop_the_chunk() {
+        assert_eq!(
+            run_chunk("local x = 1\nerror('stopped')\nx = 2"),
+            Err("test:2: stopped".to_string())
+        );
+    }
+
+    #[test]
+    fn runtime_errors_name_the_offending_variable() {
+        let cases = [
+            ("foo()", "attempt to call a nil value (global 'foo')"),
+            (
+                "local x = 1; return x.y",
+                "attempt to index a number value (local 'x')",
+            ),
+            (
+                "local t = {y = {}}; return t.y + 1",
+                "attempt to perform arithmetic on a table value (field 'y')",
+            ),
+            (
+                "local t = {}; t:m()",
+                "attempt to call a nil value (method 'm')",
+            ),
+            (
+                "local u; return (function() return u.x end)()",
+                "attempt to index a nil value (upvalue 'u')",
+            ),
+            (
+                "local s = 'a'; return s & 1",
+                "attempt to perform bitwise operation on a string value (local 's')",
+            ),
+            ("return #5", "attempt to get length of a number value"),
+            ("return 'x' .. {}", "attempt to concatenate a table value"),
+        ];
+        for (source, message) in cases {
+            assert_eq!(run_chunk(source), Err(format!("test:1: {}", message)));
+        }
+    }
+
+    #[test]
+    fn comparison_errors_name_both_types() {
+        assert_eq!(
+            run_chunk("return 1 < 'x'"),
+            Err("test:1: attempt to compare number with string".to_string())
+        );
+        assert_eq!(
+            run_chunk("return {} <= {}"),
+            Err("test:1: attempt to compare two table values".to_string())
+        );
+    }
 }